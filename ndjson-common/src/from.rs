@@ -14,18 +14,30 @@
  * limitations under the License.
  */
 
-use crate::error::NdJsonSpatialError;
-use std::io::{BufRead, BufReader, BufWriter, StdoutLock, Write};
+use crate::{error::NdJsonSpatialError, json_selector_parser::Selector};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use yajlish::{
-    ndjson_handler::{NdJsonHandler, Selector},
+    ndjson_handler::{NdJsonHandler, Selector as HandlerSelector},
     Parser,
 };
 
-pub fn generic_split<R: BufRead>(
+pub fn generic_split<R: BufRead, W: Write>(
     read: &mut R,
-    write: StdoutLock,
+    write: W,
     selectors: Vec<Selector>,
 ) -> Result<(), NdJsonSpatialError> {
+    let selectors = selectors
+        .into_iter()
+        .map(|s| match s {
+            Selector::Identifier(ident) => Ok(HandlerSelector::Identifier(ident)),
+            Selector::Index(i) => Ok(HandlerSelector::Index(i)),
+            other => Err(NdJsonSpatialError::Error(format!(
+                "Selector {:?} is not supported when splitting json",
+                other
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
     let mut buf_write = BufWriter::with_capacity(5_000_000, write);
 
     let mut handler = NdJsonHandler::new(&mut buf_write, selectors);
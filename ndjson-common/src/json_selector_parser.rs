@@ -15,16 +15,45 @@
  */
 
 use nom::{
-    complete, digit, do_parse, many0, map_res, named, opt, rest, tag, take_till1, take_while,
-    types::CompleteStr, whitespace::sp,
+    alt, complete, delimited, digit, do_parse, error_position, many0, map, map_res, named, opt,
+    preceded, recognize, recognize_float, rest, separated_list, tag, take_while, terminated,
+    types::CompleteStr, whitespace::sp, Err as NomErr, ErrorKind, IResult,
 };
-use serde_json::Value;
+use regex::Regex;
+use serde_json::{Number, Value};
 use std::{
     cmp::{Ordering, PartialOrd},
+    collections::HashSet,
+    fs::read_to_string,
     num::{ParseFloatError, ParseIntError},
     str::{FromStr, ParseBoolError},
 };
-pub use yajlish::ndjson_handler::Selector;
+
+/// One step of a json selector such as `d.features[*].properties.name`.
+/// Identifiers hold the key wrapped in double quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    Identifier(String),
+    Index(usize),
+    /// `[-n]`, the nth element from the end of an array.
+    IndexFromEnd(usize),
+    /// `[*]`, every element of an array or value of an object.
+    Wildcard,
+    /// `[start:end]`, where negative bounds count from the end of the array.
+    Slice(Option<i64>, Option<i64>),
+    /// `..name`, every value of `name` at any depth.
+    Descendant(String),
+}
+
+impl Selector {
+    /// Whether this step can select more than one value.
+    pub fn is_multiple(&self) -> bool {
+        matches!(
+            self,
+            Selector::Wildcard | Selector::Slice(_, _) | Selector::Descendant(_)
+        )
+    }
+}
 
 pub type Null = Option<NonNullJsonValue>;
 
@@ -62,6 +91,19 @@ pub enum Comparator {
     NE,
 }
 
+impl Comparator {
+    pub fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            Comparator::LT => ordering == Ordering::Less,
+            Comparator::LE => ordering != Ordering::Greater,
+            Comparator::GT => ordering == Ordering::Greater,
+            Comparator::GE => ordering != Ordering::Less,
+            Comparator::EQ => ordering == Ordering::Equal,
+            Comparator::NE => ordering != Ordering::Equal,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Compare<T> {
     comparator: Comparator,
@@ -209,16 +251,67 @@ fn parse_f64(s: CompleteStr) -> Result<f64, ParseFloatError> {
     s.parse::<f64>()
 }
 
+fn parse_number(s: CompleteStr) -> Result<Number, ()> {
+    if let Ok(i) = s.parse::<i64>() {
+        Ok(i.into())
+    } else if let Ok(u) = s.parse::<u64>() {
+        Ok(u.into())
+    } else {
+        s.parse::<f64>().ok().and_then(Number::from_f64).ok_or(())
+    }
+}
+
 fn parse_string(s: CompleteStr) -> Result<String, std::convert::Infallible> {
     s.parse()
 }
 
+/// A double quoted string with json escapes, such as `"Median Income"` or `"say \\"hi\\""`.
+fn parse_quoted_string(input: CompleteStr) -> IResult<CompleteStr, String> {
+    let s = input.0;
+    if s.starts_with('"') {
+        let mut escaped = false;
+        for (idx, c) in s.char_indices().skip(1) {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                return match serde_json::from_str::<String>(&s[..=idx]) {
+                    Ok(v) => Ok((CompleteStr(&s[idx + 1..]), v)),
+                    Err(_) => Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0)))),
+                };
+            }
+        }
+    }
+    Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0))))
+}
+
+fn non_empty(identifier: String) -> Result<String, ()> {
+    if identifier.is_empty() {
+        Err(())
+    } else {
+        Ok(identifier)
+    }
+}
+
+fn quoted_identifier(key: String) -> String {
+    format!("\"{}\"", key)
+}
+
+named!(
+    parse_identifier<CompleteStr, String>,
+    alt!(
+        complete!(parse_quoted_string) |
+        map!(take_while!(is_not_dot_or_array_bracket_or_comparator), |s| s.0.to_string())
+    )
+);
+
 named!(
     parse_self_signifier<CompleteStr, Option<Selector>>,
     do_parse!(
         tag!("d") >>
-        index: opt!(complete!(parse_index)) >>
-        (index.map(Selector::Index))
+        index: opt!(complete!(parse_bracket)) >>
+        (index)
     )
 );
 
@@ -232,18 +325,63 @@ named!(
     )
 );
 
+named!(
+    parse_signed<CompleteStr, i64>,
+    map_res!(recognize!(preceded!(opt!(tag!("-")), digit)), parse_i64)
+);
+
+named!(
+    parse_bracket<CompleteStr, Selector>,
+    alt!(
+        map!(complete!(parse_index), Selector::Index) |
+        complete!(delimited!(
+            tag!("["),
+            alt!(
+                map!(tag!("*"), |_| Selector::Wildcard) |
+                map!(parse_quoted_string, |k| Selector::Identifier(quoted_identifier(k))) |
+                do_parse!(
+                    start: opt!(parse_signed) >>
+                    tag!(":") >>
+                    end: opt!(parse_signed) >>
+                    (Selector::Slice(start, end))
+                ) |
+                do_parse!(
+                    tag!("-") >>
+                    index: map_res!(digit, parse_usize) >>
+                    (Selector::IndexFromEnd(index))
+                )
+            ),
+            tag!("]")
+        ))
+    )
+);
+
 named!(
     parse_dot_plus_identifier<CompleteStr, (Selector, Option<Selector>)>,
     do_parse!(
         tag!(".") >>
-        identifier: take_while!(is_not_dot_or_array_bracket_or_comparator) >>
-        index: opt!(parse_index) >>
-        (Selector::Identifier(format!("\"{}\"", identifier)), index.map(Selector::Index))
+        identifier: parse_identifier >>
+        index: opt!(parse_bracket) >>
+        (Selector::Identifier(quoted_identifier(identifier)), index)
+    )
+);
+
+named!(
+    parse_descendant<CompleteStr, (Selector, Option<Selector>)>,
+    do_parse!(
+        tag!("..") >>
+        identifier: map_res!(parse_identifier, non_empty) >>
+        index: opt!(parse_bracket) >>
+        (Selector::Descendant(quoted_identifier(identifier)), index)
     )
 );
 
 fn is_not_dot_or_array_bracket_or_comparator(c: char) -> bool {
-    !is_dot(c) && !is_array_bracket(c) && !is_comparator(c) && c != ' '
+    !is_dot(c) && !is_array_bracket(c) && !is_comparator(c) && !is_delimiter(c) && c != ' '
+}
+
+fn is_delimiter(c: char) -> bool {
+    c == ')' || c == ','
 }
 
 fn is_dot(c: char) -> bool {
@@ -275,7 +413,11 @@ fn combine_identifiers(
 
 named!(
     parse_many_identifiers<CompleteStr, Vec<(Selector, Option<Selector>)>>,
-    many0!(complete!(parse_dot_plus_identifier))
+    many0!(alt!(
+        complete!(parse_descendant) |
+        complete!(parse_dot_plus_identifier) |
+        map!(complete!(parse_bracket), |b| (b, None))
+    ))
 );
 
 named!(
@@ -287,6 +429,23 @@ named!(
     )
 );
 
+/// Splits `selector:suffix` at the first colon outside a quoted key, for arguments
+/// such as `d.properties.name:name` or `d.tags."addr:street":desc`.
+pub fn split_selector_suffix(expression: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in expression.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ':' if !quoted => return (&expression[..idx], Some(&expression[idx + 1..])),
+            _ => {}
+        }
+    }
+    (expression, None)
+}
+
 fn is_comparator(c: char) -> bool {
     c == '<' || c == '=' || c == '!' || c == '>'
 }
@@ -305,17 +464,12 @@ fn comparator(c: CompleteStr) -> Result<Comparator, ()> {
 
 named!(
     parse_comparator<CompleteStr, Comparator>,
-    map_res!(take_till1!(is_digit_or_space), comparator)
+    map_res!(
+        alt!(tag!("<=") | tag!(">=") | tag!("==") | tag!("!=") | tag!("<") | tag!(">")),
+        comparator
+    )
 );
 
-fn is_digit(c: char) -> bool {
-    c.is_digit(10)
-}
-
-fn is_digit_or_space(c: char) -> bool {
-    is_digit(c) || c == ' '
-}
-
 named!(
     parse_value_f64<CompleteStr, f64>,
     map_res!(rest, parse_f64)
@@ -451,6 +605,710 @@ named!(
     )
 );
 
+/// A comparison against a literal value, typed by that value.
+#[derive(Debug, PartialEq)]
+pub enum Predicate {
+    I64(Compare<i64>),
+    F64(Compare<f64>),
+    Bool(Compare<bool>),
+    Null(Compare<Null>),
+    String(Compare<String>),
+}
+
+impl Predicate {
+    pub fn compare(&self, other: Value) -> bool {
+        match self {
+            Predicate::I64(c) => c.compare(other),
+            Predicate::F64(c) => c.compare(other),
+            Predicate::Bool(c) => c.compare(other),
+            Predicate::Null(c) => c.compare(other),
+            Predicate::String(c) => c.compare(other),
+        }
+    }
+}
+
+/// A string matching test. Non-string json values are matched by their string form.
+#[derive(Debug)]
+pub enum StringMatch {
+    Regex(Regex),
+    Contains(String),
+    StartsWith(String),
+    EndsWith(String),
+    IEq(String),
+}
+
+impl StringMatch {
+    pub fn is_match(&self, other: Value) -> bool {
+        String::parse_value(other)
+            .map(|o| match self {
+                StringMatch::Regex(r) => r.is_match(&o),
+                StringMatch::Contains(s) => o.contains(s.as_str()),
+                StringMatch::StartsWith(s) => o.starts_with(s.as_str()),
+                StringMatch::EndsWith(s) => o.ends_with(s.as_str()),
+                StringMatch::IEq(s) => o.to_lowercase() == *s,
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl PartialEq for StringMatch {
+    fn eq(&self, other: &StringMatch) -> bool {
+        use StringMatch::{Contains, EndsWith, IEq, Regex, StartsWith};
+        match (self, other) {
+            (Regex(r), Regex(o)) => r.as_str() == o.as_str(),
+            (Contains(s), Contains(o)) => s == o,
+            (StartsWith(s), StartsWith(o)) => s == o,
+            (EndsWith(s), EndsWith(o)) => s == o,
+            (IEq(s), IEq(o)) => s == o,
+            _ => false,
+        }
+    }
+}
+
+/// The values of an `in` list. Unquoted values match by their inferred type,
+/// as with `==`, and also by their literal text, so `027` matches both `27` and `"027"`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ValueSet {
+    i64s: HashSet<i64>,
+    f64s: Vec<f64>,
+    bools: HashSet<bool>,
+    null: bool,
+    strings: HashSet<String>,
+}
+
+impl ValueSet {
+    fn insert(&mut self, value: ValueToken) {
+        match value {
+            ValueToken::Quoted(value) => {
+                self.strings.insert(value);
+            }
+            ValueToken::Bare(value) => {
+                if let Ok(v) = parse_i64(value.as_str().into()) {
+                    self.i64s.insert(v);
+                } else if let Ok(v) = parse_f64(value.as_str().into()) {
+                    self.f64s.push(v);
+                } else if let Ok(v) = parse_bool(value.as_str().into()) {
+                    self.bools.insert(v);
+                } else if parse_null(value.as_str().into()).is_ok() {
+                    self.null = true;
+                }
+                self.strings.insert(value);
+            }
+        }
+    }
+
+    pub fn contains(&self, other: Value) -> bool {
+        i64::parse_value(other.clone())
+            .map(|v| self.i64s.contains(&v))
+            .unwrap_or(false)
+            || f64::parse_value(other.clone())
+                .map(|v| self.f64s.contains(&v))
+                .unwrap_or(false)
+            || bool::parse_value(other.clone())
+                .map(|v| self.bools.contains(&v))
+                .unwrap_or(false)
+            || (self.null && other.is_null())
+            || String::parse_value(other)
+                .map(|v| self.strings.contains(&v))
+                .unwrap_or(false)
+    }
+}
+
+/// The json types that can be tested for with `is`.
+#[derive(Debug, PartialEq)]
+pub enum JsonType {
+    String,
+    Number,
+    Integer,
+    Bool,
+    Null,
+    Object,
+    Array,
+}
+
+impl JsonType {
+    pub fn is_type(&self, other: &Value) -> bool {
+        match self {
+            JsonType::String => other.is_string(),
+            JsonType::Number => other.is_number(),
+            JsonType::Integer => other.is_i64() || other.is_u64(),
+            JsonType::Bool => other.is_boolean(),
+            JsonType::Null => other.is_null(),
+            JsonType::Object => other.is_object(),
+            JsonType::Array => other.is_array(),
+        }
+    }
+}
+
+/// A test applied to the value a selector picks out of each json object.
+#[derive(Debug, PartialEq)]
+pub enum Test {
+    Compare(Predicate),
+    StringMatch(StringMatch),
+    In(ValueSet),
+    NotIn(ValueSet),
+    Between(Predicate, Predicate),
+    Is(JsonType),
+}
+
+impl Test {
+    pub fn test(&self, other: Value) -> bool {
+        match self {
+            Test::Compare(predicate) => predicate.compare(other),
+            Test::StringMatch(string_match) => string_match.is_match(other),
+            Test::In(values) => values.contains(other),
+            Test::NotIn(values) => !values.contains(other),
+            Test::Between(low, high) => low.compare(other.clone()) && high.compare(other),
+            Test::Is(json_type) => json_type.is_type(&other),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArithmeticOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// The value a test is applied to.
+#[derive(Debug, PartialEq)]
+pub enum Operand {
+    /// A selection from the json object, starting with `d`.
+    Selector(Vec<Selector>),
+    /// A selection from the current array element inside `any` or `all`, starting with `x`.
+    Element(Vec<Selector>),
+    /// `len(operand)`, the length of an array, object or string.
+    Len(Box<Operand>),
+    /// A number literal in an arithmetic expression.
+    Number(Number),
+    Arithmetic(Box<Operand>, ArithmeticOperator, Box<Operand>),
+}
+
+impl Operand {
+    fn is_selection(&self) -> bool {
+        matches!(
+            self,
+            Operand::Selector(_) | Operand::Element(_) | Operand::Len(_)
+        )
+    }
+}
+
+/// A filter expression: tests, `exists(operand)` and array quantifiers combined with
+/// `&&`, `||`, `!` and parentheses.
+#[derive(Debug, PartialEq)]
+pub enum Expression {
+    Test(Test, Operand),
+    /// A comparison between two operands where at least one is computed,
+    /// such as `d.pop / d.area > 500` or `d.end > d.start`.
+    Compare(Operand, Comparator, Operand),
+    Exists(Operand),
+    Any(Operand, Box<Expression>),
+    All(Operand, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+}
+
+#[derive(Debug, PartialEq)]
+enum ValueToken {
+    Quoted(String),
+    Bare(String),
+}
+
+/// Scans a double-quoted json string, or an unquoted value that runs until
+/// one of `terminators` or an unbalanced `)`. An unquoted value cannot start
+/// with `(`, so `d.a == (5)` fails rather than comparing with the string `(5)`.
+fn scan_value_token<'a>(
+    input: CompleteStr<'a>,
+    terminators: &[&str],
+) -> IResult<CompleteStr<'a>, ValueToken> {
+    let s = input.0;
+    if s.starts_with('"') {
+        return map!(input, parse_quoted_string, ValueToken::Quoted);
+    }
+    if s.starts_with('(') {
+        return Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+
+    let mut depth = 0;
+    let mut end = s.len();
+    for (idx, c) in s.char_indices() {
+        if c == '(' {
+            depth += 1;
+        } else if c == ')' {
+            if depth == 0 {
+                end = idx;
+                break;
+            }
+            depth -= 1;
+        } else if terminators.iter().any(|t| s[idx..].starts_with(t)) {
+            end = idx;
+            break;
+        }
+    }
+    let value = s[..end].trim_end();
+    if value.is_empty() {
+        return Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0))));
+    }
+    Ok((
+        CompleteStr(&s[value.len()..]),
+        ValueToken::Bare(value.to_string()),
+    ))
+}
+
+fn parse_value_token(input: CompleteStr) -> IResult<CompleteStr, ValueToken> {
+    scan_value_token(input, &["&&", "||"])
+}
+
+fn parse_list_value_token(input: CompleteStr) -> IResult<CompleteStr, ValueToken> {
+    scan_value_token(input, &[",", "]", "&&", "||"])
+}
+
+fn parse_lower_bound_token(input: CompleteStr) -> IResult<CompleteStr, ValueToken> {
+    scan_value_token(input, &[" and ", "&&", "||"])
+}
+
+/// Parses a `/regex/` literal, with an optional trailing `i` for case-insensitive matching.
+fn parse_regex(input: CompleteStr) -> IResult<CompleteStr, StringMatch> {
+    let s = input.0;
+    if !s.starts_with('/') {
+        return Err(NomErr::Error(error_position!(input, ErrorKind::Custom(1))));
+    }
+    let mut pattern = String::new();
+    let mut escaped = false;
+    for (idx, c) in s.char_indices().skip(1) {
+        if escaped {
+            if c != '/' {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '/' {
+            let (remaining, pattern) = if s[idx + 1..].starts_with('i') {
+                (&s[idx + 2..], format!("(?i){}", pattern))
+            } else {
+                (&s[idx + 1..], pattern)
+            };
+            return match Regex::new(&pattern) {
+                Ok(r) => Ok((CompleteStr(remaining), StringMatch::Regex(r))),
+                Err(_) => Err(NomErr::Error(error_position!(input, ErrorKind::Custom(1)))),
+            };
+        } else {
+            pattern.push(c);
+        }
+    }
+    Err(NomErr::Error(error_position!(input, ErrorKind::Custom(1))))
+}
+
+fn value_token_string(value: ValueToken) -> String {
+    match value {
+        ValueToken::Quoted(v) | ValueToken::Bare(v) => v,
+    }
+}
+
+named!(
+    parse_string_match_operator<CompleteStr, StringMatch>,
+    alt!(
+        do_parse!(tag!("=~") >> opt!(sp) >> r: parse_regex >> (r)) |
+        do_parse!(
+            tag!("contains") >> opt!(sp) >> v: parse_value_token >>
+            (StringMatch::Contains(value_token_string(v)))
+        ) |
+        do_parse!(
+            tag!("startswith") >> opt!(sp) >> v: parse_value_token >>
+            (StringMatch::StartsWith(value_token_string(v)))
+        ) |
+        do_parse!(
+            tag!("endswith") >> opt!(sp) >> v: parse_value_token >>
+            (StringMatch::EndsWith(value_token_string(v)))
+        ) |
+        do_parse!(
+            tag!("ieq") >> opt!(sp) >> v: parse_value_token >>
+            (StringMatch::IEq(value_token_string(v).to_lowercase()))
+        )
+    )
+);
+
+fn to_value_set(values: Vec<ValueToken>) -> ValueSet {
+    let mut set = ValueSet::default();
+    for value in values {
+        set.insert(value);
+    }
+    set
+}
+
+/// Reads one value per line from the file named after an `@`.
+fn read_value_file(path: ValueToken) -> Result<ValueSet, std::io::Error> {
+    let contents = read_to_string(value_token_string(path))?;
+    Ok(to_value_set(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| ValueToken::Bare(line.to_string()))
+            .collect(),
+    ))
+}
+
+named!(
+    parse_value_set<CompleteStr, ValueSet>,
+    alt!(
+        map!(
+            delimited!(
+                terminated!(tag!("["), opt!(sp)),
+                separated_list!(
+                    delimited!(opt!(sp), tag!(","), opt!(sp)),
+                    parse_list_value_token
+                ),
+                preceded!(opt!(sp), tag!("]"))
+            ),
+            to_value_set
+        ) |
+        map_res!(preceded!(tag!("@"), parse_value_token), read_value_file)
+    )
+);
+
+named!(
+    parse_membership<CompleteStr, Test>,
+    alt!(
+        do_parse!(tag!("in") >> opt!(sp) >> values: parse_value_set >> (Test::In(values))) |
+        do_parse!(
+            tag!("not") >> sp >> tag!("in") >> opt!(sp) >>
+            values: parse_value_set >>
+            (Test::NotIn(values))
+        )
+    )
+);
+
+named!(
+    parse_between<CompleteStr, Test>,
+    do_parse!(
+        tag!("between") >>
+        opt!(sp) >>
+        low: parse_lower_bound_token >>
+        opt!(sp) >>
+        tag!("and") >>
+        opt!(sp) >>
+        high: parse_value_token >>
+        (Test::Between(
+            to_predicate(Comparator::GE, low),
+            to_predicate(Comparator::LE, high)
+        ))
+    )
+);
+
+named!(
+    parse_json_type<CompleteStr, JsonType>,
+    alt!(
+        map!(tag!("string"), |_| JsonType::String) |
+        map!(tag!("number"), |_| JsonType::Number) |
+        map!(tag!("integer"), |_| JsonType::Integer) |
+        map!(tag!("bool"), |_| JsonType::Bool) |
+        map!(tag!("null"), |_| JsonType::Null) |
+        map!(tag!("object"), |_| JsonType::Object) |
+        map!(tag!("array"), |_| JsonType::Array)
+    )
+);
+
+fn to_predicate(comparator: Comparator, value: ValueToken) -> Predicate {
+    let value = match value {
+        ValueToken::Quoted(value) => return Predicate::String(Compare { comparator, value }),
+        ValueToken::Bare(value) => value,
+    };
+    if let Ok(value) = parse_i64(value.as_str().into()) {
+        Predicate::I64(Compare { comparator, value })
+    } else if let Ok(value) = parse_f64(value.as_str().into()) {
+        Predicate::F64(Compare { comparator, value })
+    } else if let Ok(value) = parse_bool(value.as_str().into()) {
+        Predicate::Bool(Compare { comparator, value })
+    } else if let Ok(value) = parse_null(value.as_str().into()) {
+        Predicate::Null(Compare { comparator, value })
+    } else {
+        Predicate::String(Compare { comparator, value })
+    }
+}
+
+named!(
+    parse_test_operator<CompleteStr, Test>,
+    alt!(
+        complete!(map!(parse_string_match_operator, Test::StringMatch)) |
+        complete!(parse_membership) |
+        complete!(parse_between) |
+        complete!(do_parse!(tag!("is") >> sp >> json_type: parse_json_type >> (Test::Is(json_type)))) |
+        complete!(do_parse!(
+            comparator: parse_comparator >>
+            opt!(sp) >>
+            value: parse_value_token >>
+            (Test::Compare(to_predicate(comparator, value)))
+        ))
+    )
+);
+
+named!(
+    parse_element_signifier<CompleteStr, Option<Selector>>,
+    do_parse!(
+        tag!("x") >>
+        index: opt!(complete!(parse_bracket)) >>
+        (index)
+    )
+);
+
+named!(
+    parse_element_selector<CompleteStr, Vec<Selector>>,
+    do_parse!(
+        first_array_selection: parse_element_signifier >>
+        identifiers: parse_many_identifiers >>
+        (combine_identifiers(first_array_selection, identifiers))
+    )
+);
+
+named!(
+    parse_operand<CompleteStr, Operand>,
+    alt!(
+        complete!(do_parse!(
+            tag!("len") >>
+            operand: delimited!(
+                terminated!(tag!("("), opt!(sp)),
+                parse_operand,
+                preceded!(opt!(sp), tag!(")"))
+            ) >>
+            (Operand::Len(Box::new(operand)))
+        )) |
+        map!(complete!(terminated!(parse_json_selector, selector_end)), Operand::Selector) |
+        map!(complete!(terminated!(parse_element_selector, selector_end)), Operand::Element)
+    )
+);
+
+/// Succeeds without consuming input when a selector cannot continue, so that bare
+/// values such as `delaware` or `xl` are not read as `d` or `x` followed by junk.
+fn selector_end(input: CompleteStr) -> IResult<CompleteStr, ()> {
+    match input.chars().next() {
+        Some(c) if c.is_alphanumeric() || c == '_' || c == '"' => {
+            Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0))))
+        }
+        _ => Ok((input, ())),
+    }
+}
+
+named!(
+    parse_arithmetic_factor<CompleteStr, Operand>,
+    alt!(
+        complete!(delimited!(
+            terminated!(tag!("("), opt!(sp)),
+            parse_arithmetic,
+            preceded!(opt!(sp), tag!(")"))
+        )) |
+        complete!(parse_operand) |
+        map!(map_res!(complete!(recognize_float), parse_number), Operand::Number)
+    )
+);
+
+fn fold_arithmetic(first: Operand, rest: Vec<(ArithmeticOperator, Operand)>) -> Operand {
+    rest.into_iter().fold(first, |acc, (operator, operand)| {
+        Operand::Arithmetic(Box::new(acc), operator, Box::new(operand))
+    })
+}
+
+/// The whitespace around a binary operator. Unlike `sp` it cannot be empty, so that
+/// values such as `2020-01-01` or `555-1234` are compared as literals, not computed.
+fn operator_space(input: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+    match sp(input)? {
+        (_, space) if space.is_empty() => {
+            Err(NomErr::Error(error_position!(input, ErrorKind::Custom(0))))
+        }
+        result => Ok(result),
+    }
+}
+
+named!(
+    parse_arithmetic_term<CompleteStr, Operand>,
+    do_parse!(
+        first: parse_arithmetic_factor >>
+        rest: many0!(complete!(do_parse!(
+            operator_space >>
+            operator: alt!(
+                map!(tag!("*"), |_| ArithmeticOperator::Mul) |
+                map!(tag!("/"), |_| ArithmeticOperator::Div) |
+                map!(tag!("%"), |_| ArithmeticOperator::Rem)
+            ) >>
+            operator_space >>
+            operand: parse_arithmetic_factor >>
+            ((operator, operand))
+        ))) >>
+        (fold_arithmetic(first, rest))
+    )
+);
+
+named!(
+    parse_arithmetic<CompleteStr, Operand>,
+    do_parse!(
+        first: parse_arithmetic_term >>
+        rest: many0!(complete!(do_parse!(
+            operator_space >>
+            operator: alt!(
+                map!(tag!("+"), |_| ArithmeticOperator::Add) |
+                map!(tag!("-"), |_| ArithmeticOperator::Sub)
+            ) >>
+            operator_space >>
+            operand: parse_arithmetic_term >>
+            ((operator, operand))
+        ))) >>
+        (fold_arithmetic(first, rest))
+    )
+);
+
+/// A plain `selector comparator literal` is left to `parse_test`, which types the literal.
+/// So is a bare `d` or `x` on the right, which is the literal string, as in `d.name == x`.
+fn to_arithmetic_comparison(
+    (left, comparator, right): (Operand, Comparator, Operand),
+) -> Result<Expression, ()> {
+    let bare_right = match &right {
+        Operand::Selector(s) | Operand::Element(s) => s.is_empty(),
+        _ => false,
+    };
+    if bare_right || (left.is_selection() && matches!(right, Operand::Number(_))) {
+        Err(())
+    } else {
+        Ok(Expression::Compare(left, comparator, right))
+    }
+}
+
+named!(
+    parse_arithmetic_comparison<CompleteStr, Expression>,
+    map_res!(
+        do_parse!(
+            left: parse_arithmetic >>
+            opt!(sp) >>
+            comparator: parse_comparator >>
+            opt!(sp) >>
+            right: parse_arithmetic >>
+            ((left, comparator, right))
+        ),
+        to_arithmetic_comparison
+    )
+);
+
+named!(
+    parse_test<CompleteStr, Expression>,
+    do_parse!(
+        operand: parse_operand >>
+        opt!(sp) >>
+        test: parse_test_operator >>
+        (Expression::Test(test, operand))
+    )
+);
+
+named!(
+    parse_quantifier<CompleteStr, Expression>,
+    do_parse!(
+        any: alt!(map!(tag!("any"), |_| true) | map!(tag!("all"), |_| false)) >>
+        opt!(sp) >>
+        tag!("(") >>
+        opt!(sp) >>
+        operand: parse_operand >>
+        opt!(sp) >>
+        tag!(",") >>
+        expression: parse_expression >>
+        tag!(")") >>
+        (if any {
+            Expression::Any(operand, Box::new(expression))
+        } else {
+            Expression::All(operand, Box::new(expression))
+        })
+    )
+);
+
+fn fold_and(first: Expression, rest: Vec<Expression>) -> Expression {
+    rest.into_iter()
+        .fold(first, |acc, e| Expression::And(Box::new(acc), Box::new(e)))
+}
+
+fn fold_or(first: Expression, rest: Vec<Expression>) -> Expression {
+    rest.into_iter()
+        .fold(first, |acc, e| Expression::Or(Box::new(acc), Box::new(e)))
+}
+
+named!(
+    parse_exists<CompleteStr, Expression>,
+    do_parse!(
+        exists: alt!(map!(tag!("exists"), |_| true) | map!(tag!("missing"), |_| false)) >>
+        operand: delimited!(
+            terminated!(tag!("("), opt!(sp)),
+            parse_operand,
+            preceded!(opt!(sp), tag!(")"))
+        ) >>
+        (if exists {
+            Expression::Exists(operand)
+        } else {
+            Expression::Not(Box::new(Expression::Exists(operand)))
+        })
+    )
+);
+
+named!(
+    parse_parenthesized<CompleteStr, Expression>,
+    delimited!(tag!("("), parse_expression, tag!(")"))
+);
+
+named!(
+    parse_not<CompleteStr, Expression>,
+    do_parse!(
+        tag!("!") >>
+        opt!(sp) >>
+        expression: parse_unary >>
+        (Expression::Not(Box::new(expression)))
+    )
+);
+
+named!(
+    parse_unary<CompleteStr, Expression>,
+    alt!(
+        complete!(parse_not) |
+        complete!(parse_arithmetic_comparison) |
+        complete!(parse_parenthesized) |
+        complete!(parse_exists) |
+        complete!(parse_quantifier) |
+        complete!(parse_test)
+    )
+);
+
+named!(
+    parse_and_expression<CompleteStr, Expression>,
+    do_parse!(
+        first: parse_unary >>
+        rest: many0!(complete!(do_parse!(
+            opt!(sp) >>
+            tag!("&&") >>
+            opt!(sp) >>
+            expression: parse_unary >>
+            (expression)
+        ))) >>
+        (fold_and(first, rest))
+    )
+);
+
+named!(
+    pub parse_expression<CompleteStr, Expression>,
+    do_parse!(
+        opt!(sp) >>
+        first: parse_and_expression >>
+        rest: many0!(complete!(do_parse!(
+            opt!(sp) >>
+            tag!("||") >>
+            opt!(sp) >>
+            expression: parse_and_expression >>
+            (expression)
+        ))) >>
+        opt!(sp) >>
+        (fold_or(first, rest))
+    )
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -654,4 +1512,190 @@ mod tests {
 
         assert!(parse_selector_f64("d[55]. manager. pay".into()).is_err());
     }
+
+    #[test]
+    fn test_parse_expression_precedence() {
+        let (rest, expression) =
+            parse_expression("d.a == 1 || d.b == 2 && !(d.c == \"x && y\")".into()).unwrap();
+
+        assert_eq!(rest, "".into());
+
+        assert_eq!(
+            expression,
+            Expression::Or(
+                Box::new(Expression::Test(
+                    Test::Compare(Predicate::I64(Compare {
+                        comparator: Comparator::EQ,
+                        value: 1
+                    })),
+                    Operand::Selector(vec![Selector::Identifier("\"a\"".to_string())])
+                )),
+                Box::new(Expression::And(
+                    Box::new(Expression::Test(
+                        Test::Compare(Predicate::I64(Compare {
+                            comparator: Comparator::EQ,
+                            value: 2
+                        })),
+                        Operand::Selector(vec![Selector::Identifier("\"b\"".to_string())])
+                    )),
+                    Box::new(Expression::Not(Box::new(Expression::Test(
+                        Test::Compare(Predicate::String(Compare {
+                            comparator: Comparator::EQ,
+                            value: "x && y".to_string()
+                        })),
+                        Operand::Selector(vec![Selector::Identifier("\"c\"".to_string())])
+                    ))))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_expression_failure() {
+        assert!(parse_expression("(d.a == 1".into())
+            .map(|(rest, _)| !rest.is_empty())
+            .unwrap_or(true));
+
+        assert!(parse_expression("d.a ==".into()).is_err());
+    }
+
+    #[test]
+    fn test_parse_parenthesized_literal_failure() {
+        assert!(parse_expression("d.a == (5)".into()).is_err());
+
+        assert!(parse_expression("d.a in [1, (5)]".into()).is_err());
+
+        assert_eq!(
+            parse_expression("d.a == \"(5)\"".into()),
+            Ok((
+                "".into(),
+                Expression::Test(
+                    Test::Compare(Predicate::String(Compare {
+                        comparator: Comparator::EQ,
+                        value: "(5)".to_string()
+                    })),
+                    Operand::Selector(vec![Selector::Identifier("\"a\"".to_string())])
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_regex() {
+        assert_eq!(
+            parse_regex("/a\\/b\\d/i rest".into()),
+            Ok((
+                " rest".into(),
+                StringMatch::Regex(Regex::new("(?i)a/b\\d").unwrap())
+            ))
+        );
+
+        assert!(parse_regex("/unterminated".into()).is_err());
+
+        assert!(parse_regex("/(/".into()).is_err());
+    }
+
+    #[test]
+    fn test_parse_quantifier() {
+        assert_eq!(
+            parse_expression("any(d.tags, x.kind == bridge) && len(d.tags) > 3".into()),
+            Ok((
+                "".into(),
+                Expression::And(
+                    Box::new(Expression::Any(
+                        Operand::Selector(vec![Selector::Identifier("\"tags\"".to_string())]),
+                        Box::new(Expression::Test(
+                            Test::Compare(Predicate::String(Compare {
+                                comparator: Comparator::EQ,
+                                value: "bridge".to_string()
+                            })),
+                            Operand::Element(vec![Selector::Identifier("\"kind\"".to_string())])
+                        ))
+                    )),
+                    Box::new(Expression::Test(
+                        Test::Compare(Predicate::I64(Compare {
+                            comparator: Comparator::GT,
+                            value: 3
+                        })),
+                        Operand::Len(Box::new(Operand::Selector(vec![Selector::Identifier(
+                            "\"tags\"".to_string()
+                        )])))
+                    ))
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_json_selector_multiple() {
+        assert_eq!(
+            parse_json_selector("d.features[*].properties..name".into()),
+            Ok((
+                "".into(),
+                vec![
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::Wildcard,
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Descendant("\"name\"".to_string()),
+                ]
+            ))
+        );
+
+        assert_eq!(
+            parse_json_selector("d.coordinates[1:-1][0][-1]".into()),
+            Ok((
+                "".into(),
+                vec![
+                    Selector::Identifier("\"coordinates\"".to_string()),
+                    Selector::Slice(Some(1), Some(-1)),
+                    Selector::Index(0),
+                    Selector::IndexFromEnd(1),
+                ]
+            ))
+        );
+
+        assert_eq!(
+            parse_json_selector("d[:2]".into()),
+            Ok(("".into(), vec![Selector::Slice(None, Some(2))]))
+        );
+    }
+
+    #[test]
+    fn test_split_selector_suffix() {
+        assert_eq!(split_selector_suffix("d.a.b"), ("d.a.b", None));
+        assert_eq!(split_selector_suffix("d.a.b:name"), ("d.a.b", Some("name")));
+        assert_eq!(
+            split_selector_suffix("d.tags.\"addr:street\":desc:nulls_first"),
+            ("d.tags.\"addr:street\"", Some("desc:nulls_first"))
+        );
+    }
+
+    #[test]
+    fn test_json_selector_quoted() {
+        assert_eq!(
+            parse_json_selector("d.properties[\"Median Income\"] > 5".into()),
+            Ok((
+                " > 5".into(),
+                vec![
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Identifier("\"Median Income\"".to_string()),
+                ]
+            ))
+        );
+
+        assert_eq!(
+            parse_json_selector("d.tags.\"addr:street\"..\"a.b\"[\"say \\\"hi\\\"\"]".into()),
+            Ok((
+                "".into(),
+                vec![
+                    Selector::Identifier("\"tags\"".to_string()),
+                    Selector::Identifier("\"addr:street\"".to_string()),
+                    Selector::Descendant("\"a.b\"".to_string()),
+                    Selector::Identifier("\"say \"hi\"\"".to_string()),
+                ]
+            ))
+        );
+
+        assert!(parse_expression("d[\"Median Income\"] >= 50000".into()).is_ok());
+    }
 }
@@ -14,10 +14,174 @@
 * limitations under the License.
 */
 
-use crate::error::NdJsonSpatialError;
-use crate::ndjson::NdJsonGeojsonReader;
-use std::fs::File;
+use crate::{common::geojson_rstar_to_geojson_geometry, intersection::read_geojson_file};
+use geojson::{GeoJson, Value};
+use geojson_rstar::Feature;
+use geos::{Geom, Geometry};
+use ndjson_common::{error::NdJsonSpatialError, ndjson::NdJsonGeojsonReader};
+use rstar::{RTree, RTreeObject};
+use serde_json::Map;
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufRead, Write},
+};
+
+pub fn join_contains<R: BufRead, W: Write>(
+    reference_file: File,
+    field_name: &str,
+    reader: R,
+    writer: &mut W,
+) -> Result<(), NdJsonSpatialError> {
+    let tree = if let GeoJson::FeatureCollection(features) = read_geojson_file(reference_file)? {
+        let features = features
+            .features
+            .into_iter()
+            .filter_map(|f| f.try_into().ok())
+            .collect::<Vec<Feature>>();
+        RTree::bulk_load(features)
+    } else {
+        return Err(NdJsonSpatialError::Error(
+            "Reference file was not a feature collection.".to_string(),
+        ));
+    };
+
+    for geojson in NdJsonGeojsonReader::new(reader) {
+        match geojson {
+            Ok(GeoJson::Feature(mut feature)) => {
+                let container: Geometry = match feature.geometry.as_ref().map(|g| &g.value) {
+                    Some(Value::Polygon(_)) | Some(Value::MultiPolygon(_)) => feature
+                        .geometry
+                        .clone()
+                        .expect("geometry was checked for")
+                        .try_into()?,
+                    _ => {
+                        writeln!(
+                            std::io::stderr(),
+                            "join-contains requires Polygon or MultiPolygon features, skipping feature"
+                        )
+                        .expect("Unable to write to stderr");
+                        continue;
+                    }
+                };
+
+                let feat: Feature = feature.clone().try_into().map_err(|e| {
+                    NdJsonSpatialError::Error(format!("Error converting from Geojson: {:?}", e))
+                })?;
+
+                let mut contained = vec![];
+
+                for candidate in tree.locate_in_envelope(&feat.envelope()) {
+                    let other: Geometry =
+                        geojson_rstar_to_geojson_geometry(candidate).try_into()?;
+
+                    if container.contains(&other)? {
+                        let candidate: geojson::Feature = candidate.clone().into();
+                        contained.push(serde_json::to_value(&candidate).map_err(|e| {
+                            NdJsonSpatialError::Error(format!("Error serializing feature: {}", e))
+                        })?);
+                    }
+                }
+
+                feature
+                    .properties
+                    .get_or_insert_with(Map::new)
+                    .insert(field_name.to_string(), serde_json::Value::Array(contained));
+
+                writeln!(writer, "{}", feature.to_string()).expect("Unable to write to stdout");
+            }
+            Ok(_) => {
+                writeln!(std::io::stderr(), "Invalid ndjson, expected single feature")
+                    .expect("Unable to write to stderr");
+            }
+            Err(e) => {
+                writeln!(std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
+            }
+        }
+    }
 
-pub fn join_contains(mut reference_file: File, field_name: &str) -> Result<(), NdJsonSpatialError> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static REFERENCE: &str = "{ \"type\": \"FeatureCollection\", \"features\": [{ \"type\": \"Feature\", \"properties\": { \"id\": 1 }, \"geometry\": { \"type\": \"Point\", \"coordinates\": [1.0, 1.0] } }, { \"type\": \"Feature\", \"properties\": { \"id\": 2 }, \"geometry\": { \"type\": \"Point\", \"coordinates\": [5.0, 5.0] } }] }";
+
+    #[test]
+    fn test_join_contains_polygon_contains_point() {
+        let path = std::env::temp_dir().join("join_contains_polygon_contains_point.geojson");
+        File::create(&path)
+            .and_then(|mut f| f.write_all(REFERENCE.as_bytes()))
+            .expect("Able to write reference file");
+
+        let mut output = vec![];
+
+        join_contains(
+            File::open(&path).expect("Able to open reference file"),
+            "points",
+            "{ \"type\": \"Feature\", \"properties\": { \"name\": \"a\" }, \"geometry\": { \"type\": \"Polygon\", \"coordinates\": [[[0.0, 0.0], [2.0, 0.0], [2.0, 2.0], [0.0, 2.0], [0.0, 0.0]]] } }\n".as_bytes(),
+            &mut output,
+        )
+        .expect("Able to join contains");
+
+        let data = std::str::from_utf8(&output).expect("Some of the bytes were not utf-8");
+        let feature: serde_json::Value =
+            serde_json::from_str(data.trim()).expect("The output was not valid json");
+
+        assert_eq!(feature["properties"]["name"], "a");
+        let points = feature["properties"]["points"]
+            .as_array()
+            .expect("points was an array");
+        assert_eq!(points.len(), 1);
+        assert_eq!(
+            points[0]["geometry"]["coordinates"],
+            serde_json::json!([1.0, 1.0])
+        );
+    }
+
+    #[test]
+    fn test_join_contains_skips_non_polygon() {
+        let path = std::env::temp_dir().join("join_contains_skips_non_polygon.geojson");
+        File::create(&path)
+            .and_then(|mut f| f.write_all(REFERENCE.as_bytes()))
+            .expect("Able to write reference file");
+
+        let mut output = vec![];
+
+        join_contains(
+            File::open(&path).expect("Able to open reference file"),
+            "points",
+            "{ \"type\": \"Feature\", \"properties\": {}, \"geometry\": { \"type\": \"Point\", \"coordinates\": [1.0, 1.0] } }\n".as_bytes(),
+            &mut output,
+        )
+        .expect("Able to join contains");
+
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_join_contains_polygon_contains_nothing() {
+        let path = std::env::temp_dir().join("join_contains_polygon_contains_nothing.geojson");
+        File::create(&path)
+            .and_then(|mut f| f.write_all(REFERENCE.as_bytes()))
+            .expect("Able to write reference file");
+
+        let mut output = vec![];
+
+        join_contains(
+            File::open(&path).expect("Able to open reference file"),
+            "points",
+            "{ \"type\": \"Feature\", \"properties\": {}, \"geometry\": { \"type\": \"Polygon\", \"coordinates\": [[[10.0, 10.0], [12.0, 10.0], [12.0, 12.0], [10.0, 12.0], [10.0, 10.0]]] } }\n".as_bytes(),
+            &mut output,
+        )
+        .expect("Able to join contains");
+
+        let data = std::str::from_utf8(&output).expect("Some of the bytes were not utf-8");
+        let feature: serde_json::Value =
+            serde_json::from_str(data.trim()).expect("The output was not valid json");
+
+        assert_eq!(feature["properties"]["points"], serde_json::json!([]));
+    }
+}
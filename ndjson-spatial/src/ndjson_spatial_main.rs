@@ -22,6 +22,7 @@ mod centroid;
 mod common;
 mod from_geojson;
 mod intersection;
+mod join_contains;
 mod nearest_distance;
 mod to_geojson;
 mod transform;
@@ -81,6 +82,35 @@ fn main() {
             )
             .expect("Unable to write to stderr");
         }
+    } else if let Some("join-contains") = args.subcommand_name() {
+        let args = args
+            .subcommand_matches("join-contains")
+            .expect("subcommand was correctly tested for");
+        let filename = args.value_of("reference").expect("reference is required");
+
+        let field_name = args.value_of("field-name").expect("field-name is required");
+
+        let reference_file = match File::open(filename) {
+            Ok(r) => r,
+            Err(e) => {
+                writeln!(::std::io::stderr(), "Error opening reference file: {}", e)
+                    .expect("Unable to write to stderr");
+                exit(1);
+            }
+        };
+        if let Err(err) = join_contains::join_contains(
+            reference_file,
+            field_name,
+            std::io::BufReader::new(std::io::stdin()),
+            &mut std::io::stdout(),
+        ) {
+            writeln!(
+                ::std::io::stderr(),
+                "Error computing join-contains {:?}",
+                err
+            )
+            .expect("Unable to write to stderr");
+        }
     } else if let Some("area") = args.subcommand_name() {
         let args = args
             .subcommand_matches("area")
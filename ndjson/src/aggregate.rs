@@ -1,19 +1,129 @@
 use crate::{
-    filter::select_from_json_object,
+    filter::{evaluate, select_value},
     join::{OrderedNumber, OrderedValue},
 };
-use itertools::Itertools;
 use ndjson_common::{
-    error::NdJsonSpatialError, json_selector_parser::Selector, ndjson::NdjsonReader,
+    error::NdJsonSpatialError,
+    json_selector_parser::{Expression, Selector},
+    ndjson::NdjsonReader,
 };
 use serde_json::{Map, Value};
-use std::io::{BufRead, Write};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+};
+use tempfile::tempfile;
 
 pub enum Aggregation {
     Count(Vec<Selector>),
     Sum(Vec<Selector>),
     Min(Vec<Selector>),
     Max(Vec<Selector>),
+    Avg(Vec<Selector>),
+    Median(Vec<Selector>),
+    /// The pth percentile, 0 to 100, interpolated between the closest values.
+    Percentile(Vec<Selector>, f64),
+    /// Median estimated in constant memory with the P² algorithm.
+    ApproxMedian(Vec<Selector>),
+    /// Percentile estimated in constant memory with the P² algorithm.
+    ApproxPercentile(Vec<Selector>, f64),
+    /// Sample standard deviation.
+    StdDev(Vec<Selector>),
+    /// Sample variance.
+    Variance(Vec<Selector>),
+    CountDistinct(Vec<Selector>),
+    /// Every value in the group, in input order.
+    Collect(Vec<Selector>),
+    /// The distinct values in the group, sorted.
+    CollectSet(Vec<Selector>),
+    First(Vec<Selector>),
+    Last(Vec<Selector>),
+    /// The most frequent value, the smallest one on ties.
+    Mode(Vec<Selector>),
+}
+
+/// An aggregation along with an optional filter on the records that contribute to
+/// it and an optional output field name replacing `<field>_<stat>`.
+pub struct Aggregator {
+    pub aggregation: Aggregation,
+    pub filter: Option<Expression>,
+    pub name: Option<String>,
+}
+
+impl From<Aggregation> for Aggregator {
+    fn from(aggregation: Aggregation) -> Self {
+        Aggregator {
+            aggregation,
+            filter: None,
+            name: None,
+        }
+    }
+}
+
+impl Aggregation {
+    fn selector(&self) -> &[Selector] {
+        match self {
+            Aggregation::Count(sel)
+            | Aggregation::Sum(sel)
+            | Aggregation::Min(sel)
+            | Aggregation::Max(sel)
+            | Aggregation::Avg(sel)
+            | Aggregation::Median(sel)
+            | Aggregation::Percentile(sel, _)
+            | Aggregation::ApproxMedian(sel)
+            | Aggregation::ApproxPercentile(sel, _)
+            | Aggregation::StdDev(sel)
+            | Aggregation::Variance(sel)
+            | Aggregation::CountDistinct(sel)
+            | Aggregation::Collect(sel)
+            | Aggregation::CollectSet(sel)
+            | Aggregation::First(sel)
+            | Aggregation::Last(sel)
+            | Aggregation::Mode(sel) => sel,
+        }
+    }
+
+    /// Parses an aggregator name such as `sum`, `median`, `p90` or `approx_p99.9`.
+    pub fn new(name: &str, selector: Vec<Selector>) -> Result<Self, NdJsonSpatialError> {
+        let percentile = |p: &str| -> Result<f64, NdJsonSpatialError> {
+            match p.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+                _ => Err(NdJsonSpatialError::Error(format!(
+                    "Percentile must be between 0 and 100, not: {}",
+                    p
+                ))),
+            }
+        };
+        match name {
+            "count" => Ok(Aggregation::Count(selector)),
+            "sum" => Ok(Aggregation::Sum(selector)),
+            "min" => Ok(Aggregation::Min(selector)),
+            "max" => Ok(Aggregation::Max(selector)),
+            "avg" | "mean" => Ok(Aggregation::Avg(selector)),
+            "median" => Ok(Aggregation::Median(selector)),
+            "approx_median" => Ok(Aggregation::ApproxMedian(selector)),
+            "stddev" => Ok(Aggregation::StdDev(selector)),
+            "variance" => Ok(Aggregation::Variance(selector)),
+            "count_distinct" => Ok(Aggregation::CountDistinct(selector)),
+            "collect" => Ok(Aggregation::Collect(selector)),
+            "collect_set" => Ok(Aggregation::CollectSet(selector)),
+            "first" => Ok(Aggregation::First(selector)),
+            "last" => Ok(Aggregation::Last(selector)),
+            "mode" => Ok(Aggregation::Mode(selector)),
+            n if n.starts_with("approx_p") => Ok(Aggregation::ApproxPercentile(
+                selector,
+                percentile(&n["approx_p".len()..])?,
+            )),
+            n if n.starts_with('p') => Ok(Aggregation::Percentile(selector, percentile(&n[1..])?)),
+            n => Err(NdJsonSpatialError::Error(format!(
+                "--agg must be one of 'count', 'sum', 'min', 'max', 'avg', 'median', 'p<N>', 'approx_median', 'approx_p<N>', 'stddev', 'variance', 'count_distinct', 'collect', 'collect_set', 'first', 'last', 'mode', not: {}",
+                n
+            ))),
+        }
+    }
 }
 
 fn strip_quotes(ident: &str) -> String {
@@ -25,186 +135,468 @@ fn strip_quotes(ident: &str) -> String {
         .to_owned()
 }
 
+fn selector_name(selectors: &[Selector]) -> String {
+    selectors
+        .iter()
+        .map(|s| match s {
+            Selector::Identifier(ident) | Selector::Descendant(ident) => strip_quotes(ident),
+            Selector::Index(i) => i.to_string(),
+            Selector::IndexFromEnd(i) => format!("-{}", i),
+            Selector::Wildcard => "*".to_string(),
+            Selector::Slice(start, end) => format!(
+                "{}:{}",
+                start.map(|i| i.to_string()).unwrap_or_default(),
+                end.map(|i| i.to_string()).unwrap_or_default()
+            ),
+        })
+        .collect::<Vec<String>>()
+        .join("_")
+}
+
+/// Records are grouped by the values of every `group_by` selector, each written to
+/// its own field; with no selectors the whole stream is one group. Records missing a
+/// group key are grouped under `null_group`.
+///
+/// Groups are kept in a hash map of accumulators, so only one accumulator per
+/// group and aggregation is held in memory. Once `max_groups` groups exist, records
+/// for new groups are spilled to temporary files by hash of their key and aggregated
+/// after the stream ends; the output is then sorted within each spilled partition.
 pub fn aggregate<I: BufRead, O: Write>(
-    aggregator: Aggregation,
-    group_by: Vec<Selector>,
+    aggregators: Vec<Aggregator>,
+    group_by: Vec<Vec<Selector>>,
+    null_group: Value,
+    max_groups: Option<usize>,
     input: &mut I,
     mut output: O,
 ) -> Result<(), NdJsonSpatialError> {
+    aggregate_records(
+        &aggregators,
+        &group_by,
+        &null_group,
+        max_groups,
+        0,
+        NdjsonReader::new(input),
+        &mut output,
+    )
+}
+
+const SPILL_PARTITIONS: u64 = 16;
+
+fn aggregate_records<R, O>(
+    aggregators: &[Aggregator],
+    group_by: &[Vec<Selector>],
+    null_group: &Value,
+    max_groups: Option<usize>,
+    depth: u64,
+    records: R,
+    output: &mut O,
+) -> Result<(), NdJsonSpatialError>
+where
+    R: Iterator<Item = Result<Value, NdJsonSpatialError>>,
+    O: Write,
+{
     let named_group_by = group_by
         .iter()
-        .map(|s| match s {
-            Selector::Identifier(ident) => strip_quotes(ident),
-            Selector::Index(i) => i.to_string(),
-        })
-        .collect::<Vec<String>>()
-        .join("_");
+        .map(|g| selector_name(g))
+        .collect::<Vec<_>>();
+
+    let mut groups: HashMap<Vec<OrderedValue>, Vec<Accumulator>> = HashMap::new();
+    let mut spilled: Vec<Option<BufWriter<File>>> = (0..SPILL_PARTITIONS).map(|_| None).collect();
+
+    for record in records {
+        let record = record?;
+        let key = group_by
+            .iter()
+            .map(|g| {
+                select_value(&record, g)
+                    .unwrap_or_else(|| null_group.clone())
+                    .into()
+            })
+            .collect::<Vec<OrderedValue>>();
+
+        if let Some(accumulators) = groups.get_mut(&key) {
+            for (accumulator, aggregator) in accumulators.iter_mut().zip(aggregators) {
+                accumulator.add(aggregator, &record);
+            }
+        } else if max_groups.map(|m| groups.len() < m).unwrap_or(true) {
+            let mut accumulators = aggregators.iter().map(Accumulator::new).collect::<Vec<_>>();
+            for (accumulator, aggregator) in accumulators.iter_mut().zip(aggregators) {
+                accumulator.add(aggregator, &record);
+            }
+            groups.insert(key, accumulators);
+        } else {
+            let mut hasher = DefaultHasher::new();
+            (depth, &key).hash(&mut hasher);
+            let partition = &mut spilled[(hasher.finish() % SPILL_PARTITIONS) as usize];
+            if partition.is_none() {
+                *partition = Some(BufWriter::new(tempfile().map_err(|e| {
+                    NdJsonSpatialError::Error(format!("Unable to create spill file: {}", e))
+                })?));
+            }
+            if let Some(writer) = partition {
+                writeln!(writer, "{}", record).map_err(|e| {
+                    NdJsonSpatialError::Error(format!("Unable to write spill file: {}", e))
+                })?;
+            }
+        }
+    }
 
-    let iter = NdjsonReader::new(input)
-        .sorted_by_key(|el| {
-            let key: Result<OrderedValue, _> =
-                select_from_json_object(el.clone()?, &group_by).map(|v| v.into());
-            key
-        })
-        .group_by(|el| select_from_json_object(el.clone()?, &group_by));
+    if group_by.is_empty() && groups.is_empty() && depth == 0 {
+        groups.insert(vec![], aggregators.iter().map(Accumulator::new).collect());
+    }
 
-    for (key, group) in &iter {
-        let key = key?;
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by(|(a, _), (b, _)| a.cmp(b));
 
+    for (key, accumulators) in groups {
         let mut named_map = Map::new();
 
-        named_map.insert(named_group_by.clone(), key);
+        for (name, key) in named_group_by.iter().zip(key) {
+            named_map.insert(name.clone(), key.into());
+        }
 
-        match &aggregator {
-            Aggregation::Count(sel) => {
-                let mut count_key = sel
-                    .iter()
-                    .map(|s| match s {
-                        Selector::Identifier(ident) => strip_quotes(ident),
-                        Selector::Index(i) => i.to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join("_");
+        for (accumulator, aggregator) in accumulators.into_iter().zip(aggregators) {
+            accumulator.finish(aggregator, &mut named_map);
+        }
 
-                count_key.push_str("_count");
+        match serde_json::to_string(&named_map) {
+            Ok(json) => {
+                writeln!(output, "{}", json).expect("Unable to write to stdout");
+            }
+            Err(e) => {
+                writeln!(std::io::stderr(), "Error serializing JSON: {}", e)
+                    .expect("Unable to write to stderr");
+            }
+        }
+    }
+
+    for writer in spilled.into_iter().flatten() {
+        let mut file = writer
+            .into_inner()
+            .map_err(|e| NdJsonSpatialError::Error(format!("Unable to write spill file: {}", e)))?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| NdJsonSpatialError::Error(format!("Unable to read spill file: {}", e)))?;
+        aggregate_records(
+            aggregators,
+            group_by,
+            null_group,
+            max_groups,
+            depth + 1,
+            NdjsonReader::new(BufReader::new(file)),
+            output,
+        )?;
+    }
+    Ok(())
+}
 
-                let count = group.count();
+/// The running state of one aggregation for one group.
+enum Accumulator {
+    Count(usize),
+    Sum(f64),
+    Min(Option<OrderedNumber>),
+    Max(Option<OrderedNumber>),
+    /// Count, mean and sum of squared differences from the mean (Welford's algorithm).
+    Moments(usize, f64, f64),
+    Numbers(Vec<f64>),
+    Quantile(P2Quantile),
+    Set(BTreeSet<OrderedValue>),
+    Values(Vec<Value>),
+    First(Option<Value>),
+    Last(Option<Value>),
+    Frequencies(BTreeMap<OrderedValue, usize>),
+}
 
-                named_map.insert(count_key, Value::from(count));
+impl Accumulator {
+    fn new(aggregator: &Aggregator) -> Self {
+        match &aggregator.aggregation {
+            Aggregation::Count(_) => Accumulator::Count(0),
+            Aggregation::Sum(_) => Accumulator::Sum(0.0),
+            Aggregation::Min(_) => Accumulator::Min(None),
+            Aggregation::Max(_) => Accumulator::Max(None),
+            Aggregation::Avg(_) | Aggregation::StdDev(_) | Aggregation::Variance(_) => {
+                Accumulator::Moments(0, 0.0, 0.0)
             }
-            Aggregation::Sum(sel) => {
-                let mut sum_key = sel
-                    .iter()
-                    .map(|s| match s {
-                        Selector::Identifier(ident) => strip_quotes(ident),
-                        Selector::Index(i) => i.to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join("_");
-
-                let number: Result<f64, _> = group
-                    .map(|el| select_from_json_object(el?, sel))
-                    .filter_map_ok(|v| {
-                        if let Value::Number(n) = v {
-                            n.as_f64()
-                        } else {
-                            None
-                        }
-                    })
-                    .sum();
-
-                sum_key.push_str("_sum");
-
-                named_map.insert(sum_key, Value::from(number?));
+            Aggregation::Median(_) | Aggregation::Percentile(_, _) => Accumulator::Numbers(vec![]),
+            Aggregation::ApproxMedian(_) => Accumulator::Quantile(P2Quantile::new(50.0)),
+            Aggregation::ApproxPercentile(_, p) => Accumulator::Quantile(P2Quantile::new(*p)),
+            Aggregation::CountDistinct(_) | Aggregation::CollectSet(_) => {
+                Accumulator::Set(BTreeSet::new())
             }
-            Aggregation::Min(sel) => {
-                let mut min_key = sel
-                    .iter()
-                    .map(|s| match s {
-                        Selector::Identifier(ident) => strip_quotes(ident),
-                        Selector::Index(i) => i.to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join("_");
-
-                let min: Result<Option<OrderedNumber>, NdJsonSpatialError> = group
-                    .map(|el| select_from_json_object(el?, sel))
-                    .filter_map_ok(|v| {
-                        if let Value::Number(n) = v {
-                            let num: OrderedNumber = n.into();
-                            Some(num)
-                        } else {
-                            None
-                        }
-                    })
-                    .try_fold(None, |min: Option<OrderedNumber>, el| {
-                        let element = el?;
-                        if let Some(m) = min {
-                            if m > element {
-                                Ok(Some(element))
-                            } else {
-                                Ok(Some(m))
-                            }
-                        } else {
-                            Ok(Some(element))
-                        }
-                    });
-
-                min_key.push_str("_min");
-                match min? {
-                    Some(OrderedNumber::Float(num)) => {
-                        named_map.insert(min_key, Value::from(num.0));
-                    }
-                    Some(OrderedNumber::PosInt(num)) => {
-                        named_map.insert(min_key, Value::from(num));
-                    }
-                    Some(OrderedNumber::NegInt(num)) => {
-                        named_map.insert(min_key, Value::from(num));
-                    }
-                    None => {
-                        named_map.insert(min_key, Value::Null);
-                    }
+            Aggregation::Collect(_) => Accumulator::Values(vec![]),
+            Aggregation::First(_) => Accumulator::First(None),
+            Aggregation::Last(_) => Accumulator::Last(None),
+            Aggregation::Mode(_) => Accumulator::Frequencies(BTreeMap::new()),
+        }
+    }
+
+    fn add(&mut self, aggregator: &Aggregator, record: &Value) {
+        if let Some(filter) = &aggregator.filter {
+            if !evaluate(filter, record) {
+                return;
+            }
+        }
+
+        if let Accumulator::Count(count) = self {
+            *count += 1;
+            return;
+        }
+
+        let value = match select_value(record, aggregator.aggregation.selector()) {
+            Some(value) => value,
+            None => return,
+        };
+
+        let number: Option<OrderedNumber> = match &value {
+            Value::Number(n) => Some(n.clone().into()),
+            _ => None,
+        };
+
+        match self {
+            Accumulator::Count(_) => {}
+            Accumulator::Sum(sum) => {
+                if let Some(n) = number {
+                    *sum += n.to_f64();
                 }
             }
-            Aggregation::Max(sel) => {
-                let mut max_key = sel
-                    .iter()
-                    .map(|s| match s {
-                        Selector::Identifier(ident) => strip_quotes(ident),
-                        Selector::Index(i) => i.to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join("_");
-
-                let max: Result<Option<OrderedNumber>, NdJsonSpatialError> = group
-                    .map(|el| select_from_json_object(el?, sel))
-                    .filter_map_ok(|v| {
-                        if let Value::Number(n) = v {
-                            Some(n.into())
-                        } else {
-                            None
-                        }
-                    })
-                    .try_fold(None, |mut max, el| {
-                        let element = el?;
-                        if let Some(m) = max {
-                            if m < element {
-                                max = Some(element)
-                            }
-                        } else {
-                            max = Some(element);
-                        }
-                        Ok(max)
-                    });
-
-                max_key.push_str("_max");
-                match max? {
-                    Some(OrderedNumber::Float(num)) => {
-                        named_map.insert(max_key, Value::from(num.0));
-                    }
-                    Some(OrderedNumber::PosInt(num)) => {
-                        named_map.insert(max_key, Value::from(num));
+            Accumulator::Min(min) => {
+                if let Some(n) = number {
+                    if min
+                        .map(|m| n.numeric_cmp(&m) == Some(Ordering::Less))
+                        .unwrap_or(true)
+                    {
+                        *min = Some(n);
                     }
-                    Some(OrderedNumber::NegInt(num)) => {
-                        named_map.insert(max_key, Value::from(num));
-                    }
-                    None => {
-                        named_map.insert(max_key, Value::Null);
+                }
+            }
+            Accumulator::Max(max) => {
+                if let Some(n) = number {
+                    if max
+                        .map(|m| n.numeric_cmp(&m) == Some(Ordering::Greater))
+                        .unwrap_or(true)
+                    {
+                        *max = Some(n);
                     }
                 }
             }
+            Accumulator::Moments(count, mean, m2) => {
+                if let Some(n) = number {
+                    let x = n.to_f64();
+                    *count += 1;
+                    let delta = x - *mean;
+                    *mean += delta / *count as f64;
+                    *m2 += delta * (x - *mean);
+                }
+            }
+            Accumulator::Numbers(numbers) => {
+                if let Some(n) = number {
+                    numbers.push(n.to_f64());
+                }
+            }
+            Accumulator::Quantile(estimator) => {
+                if let Some(n) = number {
+                    estimator.add(n.to_f64());
+                }
+            }
+            Accumulator::Set(set) => {
+                set.insert(value.into());
+            }
+            Accumulator::Values(values) => values.push(value),
+            Accumulator::First(first) => {
+                if first.is_none() {
+                    *first = Some(value);
+                }
+            }
+            Accumulator::Last(last) => *last = Some(value),
+            Accumulator::Frequencies(counts) => {
+                *counts.entry(value.into()).or_insert(0) += 1;
+            }
         }
-        match serde_json::to_string(&named_map) {
-            Ok(json) => {
-                writeln!(output, "{}", json).expect("Unable to write to stdout");
+    }
+
+    fn finish(self, aggregator: &Aggregator, named_map: &mut Map<String, Value>) {
+        let sel = aggregator.aggregation.selector();
+        let (stat, value) = match (&aggregator.aggregation, self) {
+            (Aggregation::Count(_), Accumulator::Count(count)) => {
+                ("count".to_string(), Value::from(count))
             }
-            Err(e) => {
-                writeln!(std::io::stderr(), "Error serializing JSON: {}", e)
-                    .expect("Unable to write to stderr");
+            (Aggregation::Sum(_), Accumulator::Sum(sum)) => ("sum".to_string(), Value::from(sum)),
+            (Aggregation::Min(_), Accumulator::Min(min)) => (
+                "min".to_string(),
+                min.map(Value::from).unwrap_or(Value::Null),
+            ),
+            (Aggregation::Max(_), Accumulator::Max(max)) => (
+                "max".to_string(),
+                max.map(Value::from).unwrap_or(Value::Null),
+            ),
+            (Aggregation::Avg(_), Accumulator::Moments(count, mean, _)) => (
+                "avg".to_string(),
+                float_or_null(if count == 0 { None } else { Some(mean) }),
+            ),
+            (Aggregation::Variance(_), Accumulator::Moments(count, _, m2)) => (
+                "variance".to_string(),
+                float_or_null(sample_variance(count, m2)),
+            ),
+            (Aggregation::StdDev(_), Accumulator::Moments(count, _, m2)) => (
+                "stddev".to_string(),
+                float_or_null(sample_variance(count, m2).map(f64::sqrt)),
+            ),
+            (Aggregation::Median(_), Accumulator::Numbers(numbers)) => (
+                "median".to_string(),
+                float_or_null(percentile(numbers, 50.0)),
+            ),
+            (Aggregation::Percentile(_, p), Accumulator::Numbers(numbers)) => {
+                (format!("p{}", p), float_or_null(percentile(numbers, *p)))
+            }
+            (Aggregation::ApproxMedian(_), Accumulator::Quantile(estimator)) => (
+                "approx_median".to_string(),
+                float_or_null(estimator.value()),
+            ),
+            (Aggregation::ApproxPercentile(_, p), Accumulator::Quantile(estimator)) => {
+                (format!("approx_p{}", p), float_or_null(estimator.value()))
             }
+            (Aggregation::CountDistinct(_), Accumulator::Set(set)) => {
+                ("count_distinct".to_string(), Value::from(set.len()))
+            }
+            (Aggregation::CollectSet(_), Accumulator::Set(set)) => (
+                "collect_set".to_string(),
+                Value::Array(set.into_iter().map(Value::from).collect()),
+            ),
+            (Aggregation::Collect(_), Accumulator::Values(values)) => {
+                ("collect".to_string(), Value::Array(values))
+            }
+            (Aggregation::First(_), Accumulator::First(first)) => {
+                ("first".to_string(), first.unwrap_or(Value::Null))
+            }
+            (Aggregation::Last(_), Accumulator::Last(last)) => {
+                ("last".to_string(), last.unwrap_or(Value::Null))
+            }
+            (Aggregation::Mode(_), Accumulator::Frequencies(counts)) => {
+                let mode = counts.into_iter().fold(
+                    None,
+                    |mode: Option<(OrderedValue, usize)>, (value, count)| match mode {
+                        Some((_, most)) if most >= count => mode,
+                        _ => Some((value, count)),
+                    },
+                );
+                (
+                    "mode".to_string(),
+                    mode.map(|(value, _)| value.into()).unwrap_or(Value::Null),
+                )
+            }
+            _ => unreachable!("accumulator is created from its aggregation"),
+        };
+        let name = aggregator
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{}_{}", selector_name(sel), stat));
+        named_map.insert(name, value);
+    }
+}
+
+fn float_or_null(value: Option<f64>) -> Value {
+    value
+        .and_then(serde_json::Number::from_f64)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn sample_variance(count: usize, m2: f64) -> Option<f64> {
+    if count < 2 {
+        None
+    } else {
+        Some(m2 / (count - 1) as f64)
+    }
+}
+
+/// The pth percentile, linearly interpolated between the closest ranks.
+fn percentile(mut values: Vec<f64>, p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let rank = p / 100.0 * (values.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    Some(values[lower] + (values[upper] - values[lower]) * (rank - lower as f64))
+}
+
+/// Streaming quantile estimate that keeps five markers instead of every value
+/// (Jain and Chlamtac, "The P² algorithm for dynamic calculation of quantiles").
+struct P2Quantile {
+    p: f64,
+    heights: Vec<f64>,
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+}
+
+impl P2Quantile {
+    fn new(percentile: f64) -> Self {
+        let p = percentile / 100.0;
+        P2Quantile {
+            p,
+            heights: Vec::with_capacity(5),
+            positions: [0.0, 1.0, 2.0, 3.0, 4.0],
+            desired: [0.0, 2.0 * p, 4.0 * p, 2.0 + 2.0 * p, 4.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        if self.heights.len() < 5 {
+            self.heights.push(x);
+            if self.heights.len() == 5 {
+                self.heights
+                    .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            }
+            return;
+        }
+
+        let q = &mut self.heights;
+        let k = if x < q[0] {
+            q[0] = x;
+            0
+        } else if x >= q[4] {
+            q[4] = x;
+            3
+        } else {
+            (0..4).find(|&i| x < q[i + 1]).unwrap_or(3)
+        };
+
+        for position in self.positions.iter_mut().skip(k + 1) {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments.iter()) {
+            *desired += increment;
+        }
+
+        for i in 1..4 {
+            let n = &mut self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                n[i] += d;
+            }
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        if self.heights.len() < 5 {
+            percentile(self.heights.clone(), self.p * 100.0)
+        } else {
+            Some(self.heights[2])
         }
     }
-    Ok(())
 }
 
 #[cfg(test)]
@@ -218,8 +610,10 @@ mod tests {
         let mut output = vec![];
 
         aggregate(
-            Aggregation::Sum(vec![Selector::Identifier("quz".to_owned())]),
-            vec![Selector::Identifier("foo".to_owned())],
+            vec![Aggregation::Sum(vec![Selector::Identifier("quz".to_owned())]).into()],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
             &mut input,
             &mut output,
         )
@@ -240,8 +634,10 @@ mod tests {
         let mut output = vec![];
 
         aggregate(
-            Aggregation::Min(vec![Selector::Identifier("quz".to_owned())]),
-            vec![Selector::Identifier("foo".to_owned())],
+            vec![Aggregation::Min(vec![Selector::Identifier("quz".to_owned())]).into()],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
             &mut input,
             &mut output,
         )
@@ -262,8 +658,10 @@ mod tests {
         let mut output = vec![];
 
         aggregate(
-            Aggregation::Max(vec![Selector::Identifier("quz".to_owned())]),
-            vec![Selector::Identifier("foo".to_owned())],
+            vec![Aggregation::Max(vec![Selector::Identifier("quz".to_owned())]).into()],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
             &mut input,
             &mut output,
         )
@@ -284,14 +682,17 @@ mod tests {
         let mut output = vec![];
 
         aggregate(
-            Aggregation::Sum(vec![
+            vec![Aggregation::Sum(vec![
                 Selector::Identifier("\"foo\"".to_owned()),
                 Selector::Identifier("\"baz\"".to_owned()),
-            ]),
-            vec![
+            ])
+            .into()],
+            vec![vec![
                 Selector::Identifier("\"foo\"".to_owned()),
                 Selector::Identifier("\"bar\"".to_owned()),
-            ],
+            ]],
+            Value::Null,
+            None,
             &mut input,
             &mut output,
         )
@@ -304,4 +705,230 @@ mod tests {
                 .to_owned()
         );
     }
+
+    #[test]
+    fn test_agg_multiple() {
+        let mut input = "{\"foo\": \"bar\",\"quz\": 2}\n{\"foo\":\"bar\",\"quz\": 5.5}\n{\"foo\":\"baz\", \"quz\": 3}\n".as_bytes();
+
+        let mut output = vec![];
+
+        aggregate(
+            vec![
+                Aggregation::Sum(vec![Selector::Identifier("quz".to_owned())]).into(),
+                Aggregation::Max(vec![Selector::Identifier("quz".to_owned())]).into(),
+                Aggregation::Count(vec![Selector::Identifier("foo".to_owned())]).into(),
+            ],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
+            &mut input,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"foo\":\"bar\",\"foo_count\":2,\"quz_max\":5.5,\"quz_sum\":7.5}\n{\"foo\":\"baz\",\"foo_count\":1,\"quz_max\":3,\"quz_sum\":3.0}\n"
+        );
+    }
+
+    #[test]
+    fn test_agg_statistics() {
+        let mut input = "{\"foo\":\"bar\",\"quz\":2}\n{\"foo\":\"bar\",\"quz\":4}\n{\"foo\":\"bar\",\"quz\":4}\n{\"foo\":\"bar\",\"quz\":4}\n{\"foo\":\"bar\",\"quz\":5}\n{\"foo\":\"bar\",\"quz\":5}\n{\"foo\":\"bar\",\"quz\":7}\n{\"foo\":\"bar\",\"quz\":9}\n".as_bytes();
+
+        let mut output = vec![];
+
+        let quz = || vec![Selector::Identifier("quz".to_owned())];
+
+        aggregate(
+            vec![
+                Aggregation::Avg(quz()).into(),
+                Aggregation::Median(quz()).into(),
+                Aggregation::Percentile(quz(), 25.0).into(),
+                Aggregation::Variance(quz()).into(),
+            ],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
+            &mut input,
+            &mut output,
+        )
+        .unwrap();
+
+        let expected = format!(
+            "{{\"foo\":\"bar\",\"quz_avg\":5.0,\"quz_median\":4.5,\"quz_p25\":4.0,\"quz_variance\":{}}}\n",
+            32.0 / 7.0
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_agg_composite_and_global() {
+        let input = "{\"state\":\"MN\",\"county\":\"Hennepin\",\"pop\":3}\n{\"state\":\"MN\",\"county\":\"Ramsey\",\"pop\":2}\n{\"state\":\"MN\",\"county\":\"Hennepin\",\"pop\":1}\n{\"county\":\"Cook\",\"pop\":4}\n";
+
+        let pop = || vec![Aggregation::Sum(vec![Selector::Identifier("pop".to_owned())]).into()];
+
+        let mut output = vec![];
+        aggregate(
+            pop(),
+            vec![
+                vec![Selector::Identifier("state".to_owned())],
+                vec![Selector::Identifier("county".to_owned())],
+            ],
+            Value::from("unknown"),
+            None,
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"county\":\"Hennepin\",\"pop_sum\":4.0,\"state\":\"MN\"}\n{\"county\":\"Ramsey\",\"pop_sum\":2.0,\"state\":\"MN\"}\n{\"county\":\"Cook\",\"pop_sum\":4.0,\"state\":\"unknown\"}\n"
+        );
+
+        let mut output = vec![];
+        aggregate(
+            pop(),
+            vec![],
+            Value::Null,
+            None,
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"pop_sum\":10.0}\n");
+
+        let mut output = vec![];
+        aggregate(
+            pop(),
+            vec![],
+            Value::Null,
+            None,
+            &mut "".as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"pop_sum\":0.0}\n");
+    }
+
+    #[test]
+    fn test_agg_where_as() {
+        let input = "{\"foo\":\"bar\",\"light\":\"yes\",\"quz\":2}\n{\"foo\":\"bar\",\"light\":\"no\",\"quz\":4}\n{\"foo\":\"bar\",\"light\":\"yes\",\"quz\":6}\n";
+
+        let mut output = vec![];
+
+        aggregate(
+            vec![
+                Aggregator {
+                    aggregation: Aggregation::Count(vec![Selector::Identifier("quz".to_owned())]),
+                    filter: Some(crate::filter::parse_filter("d.light == yes").unwrap()),
+                    name: Some("NUM_W_LIGHT".to_owned()),
+                },
+                Aggregator {
+                    aggregation: Aggregation::Avg(vec![Selector::Identifier("quz".to_owned())]),
+                    filter: Some(crate::filter::parse_filter("d.light == yes").unwrap()),
+                    name: None,
+                },
+                Aggregation::Avg(vec![Selector::Identifier("quz".to_owned())]).into(),
+            ],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"NUM_W_LIGHT\":2,\"foo\":\"bar\",\"quz_avg\":4.0}\n"
+        );
+    }
+
+    #[test]
+    fn test_agg_spill() {
+        let input = (0..50)
+            .map(|i| format!("{{\"foo\":{},\"quz\":{}}}\n", i % 10, i))
+            .collect::<String>();
+
+        let mut output = vec![];
+
+        aggregate(
+            vec![
+                Aggregation::Count(vec![Selector::Identifier("quz".to_owned())]).into(),
+                Aggregation::First(vec![Selector::Identifier("quz".to_owned())]).into(),
+            ],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            Some(3),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        let mut lines = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines[..3],
+            [
+                "{\"foo\":0,\"quz_count\":5,\"quz_first\":0}",
+                "{\"foo\":1,\"quz_count\":5,\"quz_first\":1}",
+                "{\"foo\":2,\"quz_count\":5,\"quz_first\":2}",
+            ]
+        );
+
+        lines.sort();
+        let expected = (0..10)
+            .map(|i| format!("{{\"foo\":{},\"quz_count\":5,\"quz_first\":{}}}", i, i))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_p2_quantile() {
+        let mut estimator = P2Quantile::new(90.0);
+        assert_eq!(estimator.value(), None);
+
+        for i in 0..10_000 {
+            estimator.add(((i * 7919) % 10_000) as f64);
+        }
+
+        let estimate = estimator.value().unwrap();
+        assert!((estimate - 9_000.0).abs() < 100.0, "{}", estimate);
+    }
+
+    #[test]
+    fn test_agg_collecting() {
+        let mut input = "{\"foo\":\"bar\",\"quz\":\"b\"}\n{\"foo\":\"bar\",\"quz\":\"a\"}\n{\"foo\":\"bar\"}\n{\"foo\":\"bar\",\"quz\":\"b\"}\n{\"foo\":\"bar\",\"quz\":{\"c\":1}}\n".as_bytes();
+
+        let mut output = vec![];
+
+        let quz = || vec![Selector::Identifier("quz".to_owned())];
+
+        aggregate(
+            vec![
+                Aggregation::CountDistinct(quz()).into(),
+                Aggregation::Collect(quz()).into(),
+                Aggregation::CollectSet(quz()).into(),
+                Aggregation::First(quz()).into(),
+                Aggregation::Last(quz()).into(),
+                Aggregation::Mode(quz()).into(),
+            ],
+            vec![vec![Selector::Identifier("foo".to_owned())]],
+            Value::Null,
+            None,
+            &mut input,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"foo\":\"bar\",\"quz_collect\":[\"b\",\"a\",\"b\",{\"c\":1}],\"quz_collect_set\":[\"a\",\"b\",{\"c\":1}],\"quz_count_distinct\":3,\"quz_first\":\"b\",\"quz_last\":{\"c\":1},\"quz_mode\":\"b\"}\n"
+        );
+    }
 }
@@ -19,12 +19,12 @@ use clap::{
     SubCommand,
 };
 use ndjson::{
-    aggregate::{aggregate, Aggregation},
+    aggregate::{aggregate, Aggregation, Aggregator},
     filter, from_json, join, pick_field,
 };
 use ndjson_common::{
     error::NdJsonSpatialError,
-    json_selector_parser::{parse_json_selector, Selector},
+    json_selector_parser::{parse_json_selector, split_selector_suffix, Selector},
 };
 use std::{
     fs::File,
@@ -50,23 +50,41 @@ fn main() {
             writeln!(::std::io::stderr(), "{:?}", err).expect("Unable to write to stderr");
         }
     } else if let Some(args) = args.subcommand_matches("pick-field") {
-        let expression = match parse_json_selector(
-            args.value_of("expression")
-                .expect("expression is required")
-                .into(),
-        ) {
-            Ok(s) => s.1,
+        let fields = match args
+            .values_of("expression")
+            .expect("expression is required")
+            .map(|e| parse_named_selector(e).map(|(selectors, name)| (selectors, name, e)))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(fields) => fields,
             Err(e) => {
                 println!("Error parsing expression: {}", e);
                 exit(1)
             }
         };
 
-        if let Err(e) = pick_field::pick_field(
-            expression,
-            &mut BufReader::with_capacity(1_000_000, &mut stdin().lock()),
-            BufWriter::with_capacity(1_000_000, stdout().lock()),
-        ) {
+        let as_array = args.is_present("array");
+
+        let result = if fields.len() == 1 && fields[0].1.is_none() && !as_array {
+            let (expression, _, _) = fields.into_iter().next().expect("one field");
+            pick_field::pick_field(
+                expression,
+                &mut BufReader::with_capacity(1_000_000, &mut stdin().lock()),
+                BufWriter::with_capacity(1_000_000, stdout().lock()),
+            )
+        } else {
+            pick_field::pick_fields(
+                fields
+                    .into_iter()
+                    .map(|(selectors, name, e)| pick_field::PickedField::new(selectors, name, e))
+                    .collect(),
+                as_array,
+                &mut BufReader::with_capacity(1_000_000, &mut stdin().lock()),
+                BufWriter::with_capacity(1_000_000, stdout().lock()),
+            )
+        };
+
+        if let Err(e) = result {
             writeln!(::std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
         }
     } else if let Some("join") = args.subcommand_name() {
@@ -110,10 +128,133 @@ fn main() {
             }
         };
 
+        let how = match args
+            .value_of("how")
+            .map(|h| h.parse::<join::JoinType>())
+            .unwrap_or(Ok(join::JoinType::Inner))
+        {
+            Ok(how) => how,
+            Err(e) => {
+                writeln!(::std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
+                exit(1)
+            }
+        };
+
+        let memory_limit = match args.value_of("memory-limit").map(parse_size) {
+            Some(Some(limit)) => limit,
+            Some(None) => {
+                writeln!(::std::io::stderr(), "Unable to parse memory-limit")
+                    .expect("Unable to write to stderr");
+                exit(1)
+            }
+            None => join::JoinOptions::default().memory_limit,
+        };
+
+        let ref_format = match args
+            .value_of("ref-format")
+            .map(|f| f.parse::<join::ReferenceFormat>())
+            .unwrap_or_else(|| Ok(join::ReferenceFormat::from_path(filename)))
+        {
+            Ok(ref_format) => ref_format,
+            Err(e) => {
+                writeln!(::std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
+                exit(1)
+            }
+        };
+
+        let key_as = match args.value_of("key-as").map(|k| k.parse::<join::KeyType>()) {
+            Some(Ok(key_as)) => Some(key_as),
+            Some(Err(e)) => {
+                writeln!(::std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
+                exit(1)
+            }
+            None => None,
+        };
+
+        let zero_pad = match args.value_of("zero-pad").map(|w| w.parse::<usize>()) {
+            Some(Ok(width)) => Some(width),
+            Some(Err(e)) => {
+                writeln!(::std::io::stderr(), "Error parsing zero-pad: {}", e)
+                    .expect("Unable to write to stderr");
+                exit(1)
+            }
+            None => None,
+        };
+
+        let key = join::KeyOptions {
+            key_as,
+            trim: args.is_present("trim-keys"),
+            fold_case: args.is_present("fold-case"),
+            zero_pad,
+        };
+
+        let strategy = match args.value_of("strategy") {
+            Some("hash") => join::JoinStrategy::Hash,
+            Some("merge") => join::JoinStrategy::Merge,
+            _ => match std::fs::metadata(filename) {
+                Ok(m) if m.len() > memory_limit as u64 => join::JoinStrategy::Merge,
+                _ => join::JoinStrategy::Hash,
+            },
+        };
+
+        let into = args
+            .value_of("into")
+            .map(|i| object_path(i, "--into"))
+            .unwrap_or_default();
+
+        let matches = if let Some(collect) = args.value_of("collect") {
+            let fields = match args
+                .values_of("collect-fields")
+                .map(|v| v.collect::<Vec<_>>())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|f| {
+                    parse_named_selector(f).map(|(selectors, name)| {
+                        let field = pick_field::PickedField::new(selectors, name, f);
+                        (field.name, field.selectors)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(fields) => fields,
+                Err(f) => {
+                    writeln!(::std::io::stderr(), "Error parsing collect-fields: {}", f)
+                        .expect("Unable to write to stderr");
+                    exit(1)
+                }
+            };
+            if let Err(e) =
+                pick_field::check_unique_names(fields.iter().map(|(name, _)| name.as_str()))
+            {
+                writeln!(::std::io::stderr(), "Error in collect-fields: {:?}", e)
+                    .expect("Unable to write to stderr");
+                exit(1)
+            }
+            join::Matches::Collect(object_path(collect, "--collect"), fields)
+        } else if args.is_present("first") {
+            join::Matches::First
+        } else {
+            join::Matches::Each
+        };
+
+        let options = join::JoinOptions {
+            how,
+            prefix: args.value_of("prefix").unwrap_or_default().to_string(),
+            suffix: args.value_of("suffix").unwrap_or_default().to_string(),
+            into,
+            deep_merge: args.is_present("deep-merge"),
+            matches,
+            strategy,
+            memory_limit,
+            ref_format,
+            key,
+        };
+
         if let Err(err) = join::join(
             &mut reference_file,
             ref_fields,
             stream_fields,
+            &options,
             std::io::stdin().lock(),
             std::io::stdout().lock(),
         ) {
@@ -140,20 +281,21 @@ fn main() {
             writeln!(std::io::stderr(), "{:?}", e).expect("Unable to write to stderr");
         }
     } else if let Some(args) = args.subcommand_matches("agg") {
-        let aggregator_selector = args
+        let mut aggregator_selector = args
             .values_of("aggregator")
-            .map(|mut v| {
-                (
-                    v.next().unwrap(),
-                    parse_json_selector(v.next().unwrap().into()).map(|v| v.1),
-                )
-            })
-            .map(|(first, second)| match (first, second) {
-                ("count", Ok(selector)) => Aggregation::Count(selector),
-                ("min", Ok(selector)) => Aggregation::Min(selector),
-                ("max", Ok(selector)) => Aggregation::Max(selector),
-                ("sum", Ok(selector)) => Aggregation::Sum(selector),
-                (&_, Err(e)) => {
+            .expect("aggregator is required")
+            .collect::<Vec<&str>>()
+            .chunks(2)
+            .map(|pair| (pair[0], parse_json_selector(pair[1].into()).map(|v| v.1)))
+            .map(|(first, second)| match second {
+                Ok(selector) => match Aggregation::new(first, selector) {
+                    Ok(aggregation) => Aggregator::from(aggregation),
+                    Err(NdJsonSpatialError::Error(e)) => {
+                        writeln!(std::io::stderr(), "{}", e).expect("Unable to write to stderr");
+                        exit(1);
+                    }
+                },
+                Err(e) => {
                     writeln!(
                         std::io::stderr(),
                         "Error parsing aggregation selector: {}",
@@ -162,24 +304,54 @@ fn main() {
                     .expect("Unable to write to stderr");
                     exit(1);
                 }
-                (a, Ok(_)) => {
-                    writeln!(
-                        std::io::stderr(),
-                        "--agg must be one of 'count', 'sum', 'min', 'max', not: {}",
-                        a
-                    )
+            })
+            .collect::<Vec<_>>();
+
+        // --where and --as apply to the closest --agg before them.
+        let aggregator_positions = args
+            .indices_of("aggregator")
+            .expect("aggregator is required")
+            .step_by(2)
+            .collect::<Vec<usize>>();
+        let preceding_aggregator = |index: usize, flag: &str| match aggregator_positions
+            .iter()
+            .rposition(|p| *p < index)
+        {
+            Some(position) => position,
+            None => {
+                writeln!(std::io::stderr(), "{} must follow an --agg", flag)
                     .expect("Unable to write to stderr");
-                    exit(1);
+                exit(1);
+            }
+        };
+        if let (Some(values), Some(indices)) = (args.values_of("where"), args.indices_of("where")) {
+            for (expression, index) in values.zip(indices) {
+                match filter::parse_filter(expression) {
+                    Ok(f) => {
+                        aggregator_selector[preceding_aggregator(index, "--where")].filter = Some(f)
+                    }
+                    Err(e) => {
+                        writeln!(std::io::stderr(), "Error: {:?}", e)
+                            .expect("Unable to write to stderr");
+                        exit(1);
+                    }
                 }
-            })
-            .expect("aggregator is required");
+            }
+        }
+        if let (Some(values), Some(indices)) = (args.values_of("as"), args.indices_of("as")) {
+            for (name, index) in values.zip(indices) {
+                aggregator_selector[preceding_aggregator(index, "--as")].name =
+                    Some(name.to_string());
+            }
+        }
 
-        let group_by_selector = match parse_json_selector(
-            args.value_of("group-by")
-                .expect("group-by is required")
-                .into(),
-        )
-        .map(|f| f.1)
+        let group_by_selector = match args
+            .values_of("group-by")
+            .map(|v| v.collect::<Vec<_>>())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|g| parse_json_selector(g.into()).map(|f| f.1))
+            .collect::<Result<Vec<_>, _>>()
         {
             Ok(s) => s,
             Err(e) => {
@@ -189,9 +361,26 @@ fn main() {
             }
         };
 
+        let null_group = args
+            .value_of("null-group")
+            .map(|n| serde_json::from_str(n).unwrap_or_else(|_| serde_json::Value::from(n)))
+            .unwrap_or(serde_json::Value::Null);
+
+        let max_groups = match args.value_of("max-groups").map(|m| m.parse::<usize>()) {
+            Some(Ok(m)) if m > 0 => Some(m),
+            None => None,
+            Some(_) => {
+                writeln!(std::io::stderr(), "--max-groups must be a positive integer")
+                    .expect("Unable to write to stderr");
+                exit(1)
+            }
+        };
+
         if let Err(e) = aggregate(
             aggregator_selector,
             group_by_selector,
+            null_group,
+            max_groups,
             &mut BufReader::with_capacity(500_000, &mut stdin().lock()),
             BufWriter::with_capacity(1_000_000, stdout().lock()),
         ) {
@@ -199,38 +388,40 @@ fn main() {
         }
     } else if let Some(args) = args.subcommand_matches("sort") {
         if let Some(fields) = args.values_of("fields") {
-            let selectors = match fields
-                .map(|f| {
-                    if let Some((selector, sort_order)) = f.split_once(':') {
-                        match parse_json_selector(selector.into()) {
-                            Ok((_, selectors)) => Ok((selectors, parse_sort_order(sort_order))),
-                            Err(e) => Err(NdJsonSpatialError::Error(format!(
-                                "Failed to parse selector: {}: {}",
-                                selector, e
-                            ))),
-                        }
-                    } else {
-                        match parse_json_selector(f.into()) {
-                            Ok((_, selectors)) => Ok((selectors, true)),
-                            Err(e) => Err(NdJsonSpatialError::Error(format!(
-                                "Failed to parse selector: {}: {}",
-                                f, e
-                            ))),
-                        }
-                    }
-                })
+            let fields = match fields
+                .map(ndjson::sort::parse_sort_field)
                 .collect::<Result<Vec<_>, _>>()
             {
-                Ok(selectors) => selectors,
+                Ok(fields) => fields,
                 Err(e) => {
                     eprintln!("{:?}", e);
                     std::process::exit(1);
                 }
             };
 
+            let limit = match args.value_of("limit").map(|l| l.parse::<usize>()) {
+                Some(Ok(limit)) => Some(limit),
+                Some(Err(e)) => {
+                    eprintln!("Unable to parse limit: {}", e);
+                    std::process::exit(1);
+                }
+                None => None,
+            };
+
+            let memory_limit = match args.value_of("memory-limit").map(parse_size) {
+                Some(Some(limit)) => limit,
+                Some(None) => {
+                    eprintln!("Unable to parse memory-limit");
+                    std::process::exit(1);
+                }
+                None => ndjson::external_sort::DEFAULT_MEMORY_LIMIT,
+            };
+
             let input = BufReader::with_capacity(2000, std::io::stdin());
 
-            if let Err(e) = ndjson::sort::sort(input, &mut std::io::stdout(), selectors) {
+            if let Err(e) =
+                ndjson::sort::sort(input, &mut std::io::stdout(), fields, limit, memory_limit)
+            {
                 eprintln!("{:?}", e);
                 std::process::exit(1);
             }
@@ -238,28 +429,83 @@ fn main() {
     }
 }
 
-fn parse_sort_order(s: &str) -> bool {
-    match s {
-        "asc" => true,
-        "a" => true,
-        "desc" => false,
-        "d" => false,
-        a => {
-            eprintln!("expected 'asc', 'a', 'desc' or 'd' found {}", a);
-            std::process::exit(1);
+/// Parses `<selector>[:<name>]`, where keys containing a colon must be quoted.
+fn parse_named_selector(expression: &str) -> Result<(Vec<Selector>, Option<String>), String> {
+    let (selector, name) = split_selector_suffix(expression);
+    match (parse_json_selector(selector.into()), name) {
+        (Ok((rest, _)), _) if !rest.is_empty() => {
+            Err(format!("{}: unexpected {}", expression, rest))
+        }
+        (Ok(_), Some("")) => Err(format!("{}: expected a name after :", expression)),
+        (Ok((_, selectors)), name) => Ok((selectors, name.map(|n| n.to_string()))),
+        (Err(err), _) => Err(format!("{}: {}", expression, err)),
+    }
+}
+
+/// Parses a selector made only of object keys, such as `d.properties.ref`, into its keys.
+fn object_path(selector: &str, flag: &str) -> Vec<String> {
+    let keys = match parse_json_selector(selector.into()) {
+        Ok((rest, selectors)) if rest.is_empty() => selectors
+            .into_iter()
+            .map(|s| match s {
+                Selector::Identifier(ident) => Some(
+                    ident
+                        .strip_prefix('"')
+                        .and_then(|i| i.strip_suffix('"'))
+                        .unwrap_or(&ident)
+                        .to_string(),
+                ),
+                _ => None,
+            })
+            .collect::<Option<Vec<String>>>(),
+        _ => None,
+    };
+    match keys {
+        Some(keys) if !keys.is_empty() => keys,
+        _ => {
+            writeln!(
+                ::std::io::stderr(),
+                "{} must be a selector of object keys, such as d.properties.ref, not: {}",
+                flag,
+                selector
+            )
+            .expect("Unable to write to stderr");
+            exit(1)
         }
     }
 }
 
+/// Parses a size in bytes with an optional K, M or G suffix. e.g. 512M
+fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
 fn parse_args<'a>() -> ArgMatches<'a> {
     app_from_crate!("../Cargo.toml")
         .subcommand(
             SubCommand::with_name("pick-field")
-                .about("picks a field from all of the ndjson objects")
+                .about("picks fields from all of the ndjson objects")
                 .arg(
                     Arg::with_name("expression")
                         .required(true)
-                        .help("the expression that yields a field"),
+                        .multiple(true)
+                        .help("the expressions that yield a field, optionally named <expression>:<name>"),
+                )
+                .arg(
+                    Arg::with_name("array")
+                        .long("array")
+                        .help("write the picked fields as an array instead of an object"),
                 ),
         )
         .subcommand(
@@ -279,6 +525,7 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                         .long("ref-fields")
                         .required(true)
                         .takes_value(true)
+                        .multiple(true)
                         .help("The fields in the reference file to join on"),
                 )
                 .arg(
@@ -286,7 +533,106 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                         .long("stream-fields")
                         .required(true)
                         .takes_value(true)
+                        .multiple(true)
                         .help("The fields in the ndjson stream to join on"),
+                )
+                .arg(
+                    Arg::with_name("how")
+                        .long("how")
+                        .takes_value(true)
+                        .possible_values(&["inner", "left", "right", "outer", "semi", "anti"])
+                        .help("Which records to write: inner (default) only joined records, left also unmatched stream records, right also unmatched reference records, outer both, semi matched stream records unmerged, anti unmatched stream records"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Prefix added to reference keys merged into stream records"),
+                )
+                .arg(
+                    Arg::with_name("suffix")
+                        .long("suffix")
+                        .takes_value(true)
+                        .help("Suffix added to reference keys merged into stream records"),
+                )
+                .arg(
+                    Arg::with_name("into")
+                        .long("into")
+                        .takes_value(true)
+                        .value_names(&["selector"])
+                        .help("Object in the stream record to merge reference records into. e.g. --into d.properties.ref"),
+                )
+                .arg(
+                    Arg::with_name("deep-merge")
+                        .long("deep-merge")
+                        .help("Merge objects found in both records, such as properties, instead of replacing them"),
+                )
+                .arg(
+                    Arg::with_name("first")
+                        .long("first")
+                        .conflicts_with("collect")
+                        .help("Merge only the first matching reference record"),
+                )
+                .arg(
+                    Arg::with_name("collect")
+                        .long("collect")
+                        .takes_value(true)
+                        .value_names(&["selector"])
+                        .help("Write one record per stream record with the matching reference records collected into an array. e.g. --collect d.properties.matches"),
+                )
+                .arg(
+                    Arg::with_name("collect-fields")
+                        .long("collect-fields")
+                        .takes_value(true)
+                        .multiple(true)
+                        .requires("collect")
+                        .value_names(&["selector[:name]"])
+                        .help("Fields of each collected reference record to keep"),
+                )
+                .arg(
+                    Arg::with_name("ref-format")
+                        .long("ref-format")
+                        .takes_value(true)
+                        .possible_values(&["ndjson", "csv", "tsv", "geojson"])
+                        .help("Format of the reference file. Defaults to csv for .csv, tsv for .tsv, a GeoJSON FeatureCollection for .geojson, otherwise ndjson"),
+                )
+                .arg(
+                    Arg::with_name("key-as")
+                        .long("key-as")
+                        .takes_value(true)
+                        .possible_values(&["string", "number"])
+                        .help("Convert key values before comparing them, so that \"027\", 27 and 27.0 can match"),
+                )
+                .arg(
+                    Arg::with_name("trim-keys")
+                        .long("trim-keys")
+                        .help("Trim whitespace from string key values"),
+                )
+                .arg(
+                    Arg::with_name("fold-case")
+                        .long("fold-case")
+                        .help("Compare string key values case-insensitively"),
+                )
+                .arg(
+                    Arg::with_name("zero-pad")
+                        .long("zero-pad")
+                        .takes_value(true)
+                        .value_names(&["width"])
+                        .help("Left-pad string key values with zeros to width, e.g. --key-as string --zero-pad 5 for FIPS codes"),
+                )
+                .arg(
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .takes_value(true)
+                        .possible_values(&["auto", "hash", "merge"])
+                        .help("hash keeps the reference file in memory, merge sorts both inputs on disk and writes records in key order. auto (default) uses merge when the reference file is larger than --memory-limit"),
+                )
+                .arg(
+                    Arg::with_name("memory-limit")
+                        .long("memory-limit")
+                        .takes_value(true)
+                        .value_names(&["size"])
+                        .help("Memory each input of a merge join may buffer before spilling to temporary files, e.g. 64M. Defaults to 512M"),
                 ),
         )
         .subcommand(
@@ -324,11 +670,18 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                     Arg::with_name("group-by")
                         .short("g")
                         .long("group-by")
-                        .help("Selector for field to group by")
+                        .help("Selector for field to group by, may be repeated. Without it the whole stream is one group")
                         .takes_value(true)
+                        .multiple(true)
                         .number_of_values(1)
-                        .value_names(&["selector"])
-                        .required(true),
+                        .value_names(&["selector"]),
+                )
+                .arg(
+                    Arg::with_name("null-group")
+                        .long("null-group")
+                        .takes_value(true)
+                        .value_names(&["value"])
+                        .help("Group key used for records missing a group-by field, as json or a string. Defaults to null"),
                 )
                 .arg(
                     Arg::with_name("aggregator")
@@ -336,9 +689,35 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                         .long("agg")
                         .takes_value(true)
                         .required(true)
+                        .multiple(true)
                         .number_of_values(2)
                         .value_names(&["aggregator", "selector"])
-                        .help("aggregation function along with selector. e.g. -a sum d.salary"),
+                        .help("aggregation function along with selector, may be repeated. e.g. -a sum d.salary -a max d.salary"),
+                )
+                .arg(
+                    Arg::with_name("where")
+                        .long("where")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_names(&["expression"])
+                        .help("filter expression limiting the records of the preceding --agg. e.g. -a count d.id --where 'd.light == yes'"),
+                )
+                .arg(
+                    Arg::with_name("as")
+                        .long("as")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_names(&["name"])
+                        .help("output field name for the preceding --agg"),
+                )
+                .arg(
+                    Arg::with_name("max-groups")
+                        .long("max-groups")
+                        .takes_value(true)
+                        .value_names(&["n"])
+                        .help("Number of groups kept in memory before records for new groups are spilled to temporary files"),
                 ),
         )
         .subcommand(
@@ -348,12 +727,26 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                 Arg::with_name("fields")
                     .short("f")
                     .long("fields")
-                    .help("Selector for fields to sort by. e.g. -f d.property_value or -f d.property_value:d")
+                    .help("Selector for fields to sort by, with options asc, desc, nulls_first, nulls_last, numeric, lexical, natural and fold_case. e.g. -f d.property_value or -f d.property_value:desc:nulls_first")
                     .takes_value(true)
                     .multiple(true)
                     .value_names(&["selector"])
                     .required(true)
             )
+            .arg(
+                Arg::with_name("memory-limit")
+                    .long("memory-limit")
+                    .takes_value(true)
+                    .value_names(&["size"])
+                    .help("Memory to buffer records in before sorted runs are spilled to temporary files, e.g. 64M. Defaults to 512M")
+            )
+            .arg(
+                Arg::with_name("limit")
+                    .long("limit")
+                    .takes_value(true)
+                    .value_names(&["n"])
+                    .help("Write only the first n sorted records, keeping only n records in memory")
+            )
         )
         .get_matches()
 }
@@ -0,0 +1,208 @@
+use crate::join::OrderedValue;
+use ndjson_common::error::NdJsonSpatialError;
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Lines, Seek, SeekFrom, Write},
+};
+use tempfile::tempfile;
+
+pub type Key = Vec<OrderedValue>;
+
+pub const DEFAULT_MEMORY_LIMIT: usize = 512 * 1024 * 1024;
+
+/// Sorts keyed records using at most about `memory_limit` bytes of buffered json.
+/// When the records do not fit, sorted runs are written to temporary files and
+/// merged, so only one record per run is held in memory while reading the result.
+/// The sort is stable.
+pub fn external_sort<'a, I>(
+    records: I,
+    compare: &'a dyn Fn(&Key, &Key) -> Ordering,
+    memory_limit: usize,
+) -> Result<SortedRecords<'a>, NdJsonSpatialError>
+where
+    I: Iterator<Item = Result<(Key, Value), NdJsonSpatialError>>,
+{
+    let mut runs = vec![];
+    let mut buffer: Vec<(Key, String)> = vec![];
+    let mut buffered = 0;
+
+    for record in records {
+        let (key, value) = record?;
+        let line = value.to_string();
+        buffered += line.len() + 16 * key.len();
+        buffer.push((key, line));
+
+        if buffered >= memory_limit {
+            runs.push(write_run(&mut buffer, compare)?);
+            buffered = 0;
+        }
+    }
+
+    buffer.sort_by(|(a, _), (b, _)| compare(a, b));
+
+    if runs.is_empty() {
+        return Ok(SortedRecords::Memory(buffer.into_iter()));
+    }
+
+    if !buffer.is_empty() {
+        runs.push(write_run(&mut buffer, compare)?);
+    }
+
+    let mut readers = vec![];
+    let mut heap = BinaryHeap::new();
+    for (run, file) in runs.into_iter().enumerate() {
+        let mut lines = BufReader::new(file).lines();
+        if let Some(head) = read_entry(&mut lines)? {
+            heap.push(HeapEntry {
+                key: head.0,
+                line: head.1,
+                run,
+                compare,
+            });
+        }
+        readers.push(lines);
+    }
+
+    Ok(SortedRecords::Merge { readers, heap })
+}
+
+fn io_error(e: std::io::Error) -> NdJsonSpatialError {
+    NdJsonSpatialError::Error(format!("Unable to use temporary sort file: {}", e))
+}
+
+/// Writes the sorted buffer as a run of `key<TAB>record` lines. Compact json never
+/// contains a raw tab, so the first tab always ends the key.
+fn write_run(
+    buffer: &mut Vec<(Key, String)>,
+    compare: &dyn Fn(&Key, &Key) -> Ordering,
+) -> Result<File, NdJsonSpatialError> {
+    buffer.sort_by(|(a, _), (b, _)| compare(a, b));
+
+    let mut writer = BufWriter::new(tempfile().map_err(io_error)?);
+    for (key, line) in buffer.drain(..) {
+        let key = Value::Array(key.into_iter().map(Value::from).collect());
+        writeln!(writer, "{}\t{}", key, line).map_err(io_error)?;
+    }
+    let mut file = writer.into_inner().map_err(|e| io_error(e.into_error()))?;
+    file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+    Ok(file)
+}
+
+fn read_entry(
+    lines: &mut Lines<BufReader<File>>,
+) -> Result<Option<(Key, String)>, NdJsonSpatialError> {
+    match lines.next() {
+        None => Ok(None),
+        Some(line) => {
+            let line = line.map_err(io_error)?;
+            let (key, record) = line.split_at(line.find('\t').ok_or_else(|| {
+                NdJsonSpatialError::Error("Corrupt temporary sort file".to_string())
+            })?);
+            let key = match serde_json::from_str(key) {
+                Ok(Value::Array(key)) => key.into_iter().map(OrderedValue::from).collect(),
+                _ => {
+                    return Err(NdJsonSpatialError::Error(
+                        "Corrupt temporary sort file".to_string(),
+                    ))
+                }
+            };
+            Ok(Some((key, record[1..].to_string())))
+        }
+    }
+}
+
+pub struct HeapEntry<'a> {
+    key: Key,
+    line: String,
+    run: usize,
+    compare: &'a dyn Fn(&Key, &Key) -> Ordering,
+}
+
+impl<'a> PartialEq for HeapEntry<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a> Eq for HeapEntry<'a> {}
+
+impl<'a> PartialOrd for HeapEntry<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for HeapEntry<'a> {
+    /// Reversed, so the max-heap pops the smallest key, and the earliest run on ties.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.compare)(&other.key, &self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+pub enum SortedRecords<'a> {
+    Memory(std::vec::IntoIter<(Key, String)>),
+    Merge {
+        readers: Vec<Lines<BufReader<File>>>,
+        heap: BinaryHeap<HeapEntry<'a>>,
+    },
+}
+
+impl<'a> Iterator for SortedRecords<'a> {
+    type Item = Result<(Key, Value), NdJsonSpatialError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, line) = match self {
+            SortedRecords::Memory(records) => records.next()?,
+            SortedRecords::Merge { readers, heap } => {
+                let entry = heap.pop()?;
+                match read_entry(&mut readers[entry.run]) {
+                    Ok(Some((key, line))) => heap.push(HeapEntry {
+                        key,
+                        line,
+                        run: entry.run,
+                        compare: entry.compare,
+                    }),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+                (entry.key, entry.line)
+            }
+        };
+        Some(
+            serde_json::from_str(&line)
+                .map(|value| (key, value))
+                .map_err(|e| NdJsonSpatialError::Error(format!("Error parsing json: {}", e))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_sort_spills() {
+        let records = (0..100).map(|i| {
+            let key = vec![OrderedValue::from(Value::from((i * 37) % 10))];
+            Ok((key, Value::from(i)))
+        });
+
+        let compare = |a: &Key, b: &Key| a.cmp(b);
+
+        let sorted = external_sort(records, &compare, 64)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(sorted.len(), 100);
+        for pair in sorted.windows(2) {
+            assert!(pair[0].0 <= pair[1].0);
+            if pair[0].0 == pair[1].0 {
+                assert!(pair[0].1.as_u64() < pair[1].1.as_u64());
+            }
+        }
+    }
+}
@@ -14,16 +14,18 @@
  * limitations under the License.
  */
 
+use crate::join::OrderedNumber;
 use ndjson_common::{
     error::NdJsonSpatialError,
-    json_selector_parser::{
-        parse_selector_bool, parse_selector_f64, parse_selector_i64, parse_selector_null,
-        parse_selector_string, Compare, ParseValue, Selector,
-    },
+    json_selector_parser::{parse_expression, ArithmeticOperator, Expression, Operand, Selector},
     ndjson::NdjsonReader,
 };
-use serde_json::Value;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use serde_json::{Number, Value};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
 
 pub fn ndjson_filter<R: BufRead, W: Write>(
     expression: String,
@@ -33,57 +35,264 @@ pub fn ndjson_filter<R: BufRead, W: Write>(
     let mut read = BufReader::with_capacity(1_000_000, read);
     let mut write = BufWriter::with_capacity(1_000_000, write);
 
-    if let Ok((_, (compare, identifiers))) = parse_selector_i64(expression.as_str().into()) {
-        write_to_stdout_if_filter_is_true(compare, identifiers, &mut read, &mut write)?;
-    } else if let Ok((_, (compare, identifiers))) = parse_selector_f64(expression.as_str().into()) {
-        write_to_stdout_if_filter_is_true(compare, identifiers, &mut read, &mut write)?;
-    } else if let Ok((_, (compare, identifiers))) = parse_selector_bool(expression.as_str().into())
-    {
-        write_to_stdout_if_filter_is_true(compare, identifiers, &mut read, &mut write)?;
-    } else if let Ok((_, (compare, identifiers))) = parse_selector_null(expression.as_str().into())
-    {
-        write_to_stdout_if_filter_is_true(compare, identifiers, &mut read, &mut write)?;
-    } else if let Ok((_, (compare, identifiers))) =
-        parse_selector_string(expression.as_str().into())
-    {
-        write_to_stdout_if_filter_is_true(compare, identifiers, &mut read, &mut write)?;
+    let expression = parse_filter(&expression)?;
+
+    write_to_stdout_if_filter_is_true(&expression, &mut read, &mut write)
+}
+
+/// Parses a whole filter expression, failing if any of it is left unparsed.
+pub fn parse_filter(expression: &str) -> Result<Expression, NdJsonSpatialError> {
+    let expression = match parse_expression(expression.into()) {
+        Ok((remaining, expression)) if remaining.is_empty() => expression,
+        Ok((remaining, _)) => {
+            return Err(NdJsonSpatialError::Error(format!(
+                "Unable to parse filter expression at: {}",
+                remaining
+            )))
+        }
+        Err(e) => {
+            return Err(NdJsonSpatialError::Error(format!(
+                "Unable to parse filter expression: {}",
+                e
+            )))
+        }
+    };
+    check_single_values(&expression)?;
+    Ok(expression)
+}
+
+/// Fails if a test or comparison is applied to a selector that can match several
+/// values. Their matches are collected into an array, which would never equal a
+/// literal, so they have to be tested one by one with `any` or `all`.
+fn check_single_values(expression: &Expression) -> Result<(), NdJsonSpatialError> {
+    match expression {
+        Expression::Test(_, operand) => check_single_value(operand),
+        Expression::Compare(left, _, right) => {
+            check_single_value(left)?;
+            check_single_value(right)
+        }
+        Expression::Exists(_) => Ok(()),
+        Expression::Any(_, expression) | Expression::All(_, expression) => {
+            check_single_values(expression)
+        }
+        Expression::And(left, right) | Expression::Or(left, right) => {
+            check_single_values(left)?;
+            check_single_values(right)
+        }
+        Expression::Not(expression) => check_single_values(expression),
     }
-    Ok(())
 }
 
-fn write_to_stdout_if_filter_is_true<T, R: BufRead, W: Write>(
-    compare: Compare<T>,
-    identifiers: Vec<Selector>,
+fn check_single_value(operand: &Operand) -> Result<(), NdJsonSpatialError> {
+    match operand {
+        Operand::Selector(identifiers) | Operand::Element(identifiers)
+            if identifiers.iter().any(Selector::is_multiple) =>
+        {
+            Err(NdJsonSpatialError::Error(
+                "A selector with [*], [start:end] or ..name can match several values, test them with any(<selector>, x ...) or all(<selector>, x ...)".to_string(),
+            ))
+        }
+        Operand::Arithmetic(left, _, right) => {
+            check_single_value(left)?;
+            check_single_value(right)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn write_to_stdout_if_filter_is_true<R: BufRead, W: Write>(
+    expression: &Expression,
     read: &mut R,
     write: &mut W,
-) -> Result<(), NdJsonSpatialError>
-where
-    T: ParseValue,
-{
+) -> Result<(), NdJsonSpatialError> {
     for value in NdjsonReader::new(read) {
         let v = value?;
-        if let Ok(value) = select_from_json_object(v.clone(), &identifiers) {
-            if compare.compare(value) {
-                writeln!(write, "{}", v).expect("unable to write to stdout");
-            }
+        if evaluate(expression, &v) {
+            writeln!(write, "{}", v).expect("unable to write to stdout");
         }
     }
     Ok(())
 }
 
+pub fn evaluate(expression: &Expression, value: &Value) -> bool {
+    evaluate_with_element(expression, value, None)
+}
+
+/// Evaluates `expression` against `value`, where `element` is the array element
+/// bound to `x` by the innermost `any` or `all`.
+fn evaluate_with_element(expression: &Expression, value: &Value, element: Option<&Value>) -> bool {
+    match expression {
+        Expression::Test(test, operand) => resolve_operand(operand, value, element)
+            .map(|selected| test.test(selected.into_owned()))
+            .unwrap_or(false),
+        Expression::Compare(left, comparator, right) => match (
+            resolve_operand(left, value, element),
+            resolve_operand(right, value, element),
+        ) {
+            (Ok(left), Ok(right)) => compare_values(&left, &right)
+                .map(|ordering| comparator.matches(ordering))
+                .unwrap_or(false),
+            _ => false,
+        },
+        Expression::Exists(operand) => {
+            match (operand, resolve_operand(operand, value, element).as_deref()) {
+                // A selector that can match several values exists only if it matched any.
+                (Operand::Selector(identifiers), Ok(Value::Array(items)))
+                | (Operand::Element(identifiers), Ok(Value::Array(items)))
+                    if identifiers.iter().any(Selector::is_multiple) =>
+                {
+                    !items.is_empty()
+                }
+                (_, result) => result.is_ok(),
+            }
+        }
+        Expression::Any(operand, expression) => {
+            match resolve_operand(operand, value, element).as_deref() {
+                Ok(Value::Array(items)) => items
+                    .iter()
+                    .any(|item| evaluate_with_element(expression, value, Some(item))),
+                _ => false,
+            }
+        }
+        Expression::All(operand, expression) => {
+            match resolve_operand(operand, value, element).as_deref() {
+                Ok(Value::Array(items)) => items
+                    .iter()
+                    .all(|item| evaluate_with_element(expression, value, Some(item))),
+                _ => false,
+            }
+        }
+        Expression::And(left, right) => {
+            evaluate_with_element(left, value, element)
+                && evaluate_with_element(right, value, element)
+        }
+        Expression::Or(left, right) => {
+            evaluate_with_element(left, value, element)
+                || evaluate_with_element(right, value, element)
+        }
+        Expression::Not(expression) => !evaluate_with_element(expression, value, element),
+    }
+}
+
+/// Resolves `operand` against `value`, borrowing what a selector picks out of it
+/// rather than cloning the whole record.
+fn resolve_operand<'a>(
+    operand: &Operand,
+    value: &'a Value,
+    element: Option<&'a Value>,
+) -> Result<Cow<'a, Value>, NdJsonSpatialError> {
+    match operand {
+        Operand::Selector(identifiers) => select_operand(value, identifiers),
+        Operand::Element(identifiers) => {
+            let element = element.ok_or_else(|| {
+                NdJsonSpatialError::Error("x can only be used inside any or all".to_string())
+            })?;
+            select_operand(element, identifiers)
+        }
+        Operand::Len(operand) => match resolve_operand(operand, value, element)?.as_ref() {
+            Value::Array(items) => Ok(Cow::Owned(Value::from(items.len()))),
+            Value::Object(map) => Ok(Cow::Owned(Value::from(map.len()))),
+            Value::String(s) => Ok(Cow::Owned(Value::from(s.chars().count()))),
+            _ => Err(NdJsonSpatialError::Error(
+                "len requires an array, object or string".to_string(),
+            )),
+        },
+        Operand::Number(n) => Ok(Cow::Owned(Value::Number(n.clone()))),
+        Operand::Arithmetic(left, operator, right) => {
+            match (
+                resolve_operand(left, value, element)?.as_ref(),
+                resolve_operand(right, value, element)?.as_ref(),
+            ) {
+                (Value::Number(left), Value::Number(right)) => {
+                    apply_arithmetic(left.clone().into(), operator, right.clone().into())
+                        .map(Cow::Owned)
+                }
+                _ => Err(NdJsonSpatialError::Error(
+                    "Arithmetic requires numbers".to_string(),
+                )),
+            }
+        }
+    }
+}
+
+/// Only a selector that can match several values builds a new value, the array of its matches.
+fn select_operand<'a>(
+    value: &'a Value,
+    identifiers: &[Selector],
+) -> Result<Cow<'a, Value>, NdJsonSpatialError> {
+    let selected = if identifiers.iter().any(Selector::is_multiple) {
+        select_value(value, identifiers).map(Cow::Owned)
+    } else {
+        select_ref(value, identifiers).map(Cow::Borrowed)
+    };
+    selected.ok_or_else(|| NdJsonSpatialError::Error("Selector did not match".to_string()))
+}
+
+/// Integer arithmetic is kept for integers and falls back to floats on overflow,
+/// inexact division, or when either side is a float.
+fn apply_arithmetic(
+    left: OrderedNumber,
+    operator: &ArithmeticOperator,
+    right: OrderedNumber,
+) -> Result<Value, NdJsonSpatialError> {
+    let integers = match (left, right) {
+        (OrderedNumber::Float(_), _) | (_, OrderedNumber::Float(_)) => None,
+        (left, right) => Value::from(left).as_i64().zip(Value::from(right).as_i64()),
+    };
+
+    if let Some((l, r)) = integers {
+        let result = match operator {
+            ArithmeticOperator::Add => l.checked_add(r),
+            ArithmeticOperator::Sub => l.checked_sub(r),
+            ArithmeticOperator::Mul => l.checked_mul(r),
+            ArithmeticOperator::Div => l
+                .checked_rem(r)
+                .filter(|rem| *rem == 0)
+                .and_then(|_| l.checked_div(r)),
+            ArithmeticOperator::Rem => l.checked_rem(r),
+        };
+        if let Some(result) = result {
+            return Ok(Value::from(result));
+        }
+    }
+
+    let (l, r) = (left.to_f64(), right.to_f64());
+    let result = match operator {
+        ArithmeticOperator::Add => l + r,
+        ArithmeticOperator::Sub => l - r,
+        ArithmeticOperator::Mul => l * r,
+        ArithmeticOperator::Div => l / r,
+        ArithmeticOperator::Rem => l % r,
+    };
+    Number::from_f64(result)
+        .map(Value::Number)
+        .ok_or_else(|| NdJsonSpatialError::Error(format!("{} is not a json number", result)))
+}
+
+fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => {
+            OrderedNumber::from(l.clone()).numeric_cmp(&OrderedNumber::from(r.clone()))
+        }
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// Selects a value out of a json object. Once a step that can select several
+/// values is reached (`[*]`, `[start:end]` or `..name`), the rest of the selector
+/// is applied to each of them and the matches are returned as an array.
 pub fn select_from_json_object(
     value: Value,
     identifiers: &[Selector],
 ) -> Result<Value, NdJsonSpatialError> {
     let mut last_value = value;
-    for identifier in identifiers {
+    for (position, identifier) in identifiers.iter().enumerate() {
         match identifier {
             Selector::Identifier(ident) => {
-                let ident = ident
-                    .strip_prefix('"')
-                    .map(|s| s.strip_suffix('"'))
-                    .flatten()
-                    .unwrap_or_else(|| ident.as_str());
+                let ident = unquote(ident);
 
                 if let Value::Array(_) = last_value {
                     return Err(NdJsonSpatialError::Error(format!(
@@ -112,11 +321,139 @@ pub fn select_from_json_object(
                     ));
                 }
             }
+            Selector::IndexFromEnd(selection) => {
+                if let Value::Array(array) = last_value {
+                    last_value = array
+                        .len()
+                        .checked_sub(*selection)
+                        .and_then(|i| array.get(i))
+                        .cloned()
+                        .ok_or_else(|| {
+                            NdJsonSpatialError::Error("Index out of bounds".to_string())
+                        })?;
+                } else {
+                    return Err(NdJsonSpatialError::Error(
+                        "Unable to index non-array".to_string(),
+                    ));
+                }
+            }
+            Selector::Wildcard | Selector::Slice(_, _) | Selector::Descendant(_) => {
+                return Ok(Value::Array(select_all_from_json_object(
+                    last_value,
+                    &identifiers[position..],
+                )));
+            }
         }
     }
     Ok(last_value)
 }
 
+/// Selects every value matching `identifiers`, skipping branches where a step does not apply.
+pub fn select_all_from_json_object(value: Value, identifiers: &[Selector]) -> Vec<Value> {
+    select_all_ref(&value, identifiers)
+        .into_iter()
+        .cloned()
+        .collect()
+}
+
+/// Like `select_from_json_object`, but borrows `value` so that only the selected value
+/// is cloned. None when the selector does not match.
+pub fn select_value(value: &Value, identifiers: &[Selector]) -> Option<Value> {
+    let multiple = identifiers
+        .iter()
+        .position(|s| s.is_multiple())
+        .unwrap_or(identifiers.len());
+    let selected = select_ref(value, &identifiers[..multiple])?;
+    if multiple == identifiers.len() {
+        Some(selected.clone())
+    } else {
+        Some(Value::Array(
+            select_all_ref(selected, &identifiers[multiple..])
+                .into_iter()
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+/// Follows keys and indexes without cloning. None when a step is missing or can
+/// select several values.
+pub fn select_ref<'a>(value: &'a Value, identifiers: &[Selector]) -> Option<&'a Value> {
+    identifiers
+        .iter()
+        .try_fold(value, |value, identifier| match (identifier, value) {
+            (Selector::Identifier(ident), Value::Object(map)) => map.get(unquote(ident)),
+            (Selector::Index(i), Value::Array(array)) => array.get(*i),
+            (Selector::IndexFromEnd(i), Value::Array(array)) if *i > 0 => {
+                array.len().checked_sub(*i).and_then(|i| array.get(i))
+            }
+            _ => None,
+        })
+}
+
+fn select_all_ref<'a>(value: &'a Value, identifiers: &[Selector]) -> Vec<&'a Value> {
+    let mut values = vec![value];
+    for identifier in identifiers {
+        values = values
+            .into_iter()
+            .flat_map(|value| select_step(value, identifier))
+            .collect();
+    }
+    values
+}
+
+fn select_step<'a>(value: &'a Value, identifier: &Selector) -> Vec<&'a Value> {
+    match (identifier, value) {
+        (Selector::Wildcard, Value::Array(array)) => array.iter().collect(),
+        (Selector::Wildcard, Value::Object(map)) => map.values().collect(),
+        (Selector::Slice(start, end), Value::Array(array)) => {
+            let len = array.len() as i64;
+            let bound = |b: i64| if b < 0 { (len + b).max(0) } else { b.min(len) } as usize;
+            let start = start.map(bound).unwrap_or(0);
+            let end = end.map(bound).unwrap_or(array.len());
+            array
+                .iter()
+                .skip(start)
+                .take(end.saturating_sub(start))
+                .collect()
+        }
+        (Selector::Descendant(ident), value) => {
+            let mut found = vec![];
+            collect_descendants(value, unquote(ident), &mut found);
+            found
+        }
+        (identifier, value) => select_ref(value, std::slice::from_ref(identifier))
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn collect_descendants<'a>(value: &'a Value, ident: &str, found: &mut Vec<&'a Value>) {
+    match value {
+        Value::Object(map) => {
+            if let Some(v) = map.get(ident) {
+                found.push(v);
+            }
+            for v in map.values() {
+                collect_descendants(v, ident, found);
+            }
+        }
+        Value::Array(array) => {
+            for v in array {
+                collect_descendants(v, ident, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn unquote(ident: &str) -> &str {
+    ident
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(ident)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,4 +554,606 @@ mod tests {
 
         assert_eq!("{\"a\":false}\n".as_bytes(), output.as_slice());
     }
+
+    #[test]
+    fn test_filter_string() {
+        let mut input = "{ \"a\": \"Virtual Currency\" }\n{ \"a\": \"Mortgage\" }\n".as_bytes();
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.a == Virtual Currency".to_string(),
+            &mut input,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"a\":\"Virtual Currency\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut input = "{ \"a\": \"Virtual Currency\" }\n{ \"a\": \"Mortgage\" }\n".as_bytes();
+
+        let mut output = vec![];
+
+        ndjson_filter("d.a != \"Mortgage\"".to_string(), &mut input, &mut output).unwrap();
+
+        assert_eq!(
+            "{\"a\":\"Virtual Currency\"}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_and_or_not() {
+        let input = "{ \"pop\": 2000, \"state\": \"MN\", \"capital\": false }\n{ \"pop\": 500, \"state\": \"MN\", \"capital\": true }\n{ \"pop\": 3000, \"state\": \"WI\", \"capital\": false }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.pop > 1000 && d.state == \"MN\"".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"capital\":false,\"pop\":2000,\"state\":\"MN\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "(d.pop > 1000 && d.state == \"MN\") || d.capital == true".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"capital\":false,\"pop\":2000,\"state\":\"MN\"}\n{\"capital\":true,\"pop\":500,\"state\":\"MN\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "!(d.state == MN)".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"capital\":false,\"pop\":3000,\"state\":\"WI\"}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_invalid_expression() {
+        let mut input = "{ \"a\": 1 }\n".as_bytes();
+
+        let mut output = vec![];
+
+        assert!(ndjson_filter("(d.a == 1".to_string(), &mut input, &mut output).is_err());
+    }
+
+    #[test]
+    fn test_filter_string_match() {
+        let input = "{ \"name\": \"Main Street\" }\n{ \"name\": \"main st\" }\n{ \"name\": \"Lake Avenue\" }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.name contains Street".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!("{\"name\":\"Main Street\"}\n".as_bytes(), output.as_slice());
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.name =~ /^main (st|street)$/i".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"name\":\"Main Street\"}\n{\"name\":\"main st\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.name startswith \"Lake\" || d.name endswith st".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"name\":\"main st\"}\n{\"name\":\"Lake Avenue\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.name ieq \"MAIN ST\"".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!("{\"name\":\"main st\"}\n".as_bytes(), output.as_slice());
+    }
+
+    #[test]
+    fn test_filter_in_and_between() {
+        let input = "{ \"STATEFP\": 27 }\n{ \"STATEFP\": \"055\" }\n{ \"STATEFP\": 6 }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.STATEFP in [27, 055, 19]".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"STATEFP\":27}\n{\"STATEFP\":\"055\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.STATEFP not in [27, \"055\"]".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!("{\"STATEFP\":6}\n".as_bytes(), output.as_slice());
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.STATEFP between 5 and 27.5".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"STATEFP\":27}\n{\"STATEFP\":6}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_in_file() {
+        let path = std::env::temp_dir().join("ndjson_filter_in_file_codes.txt");
+        std::fs::write(&path, "27\n\n19\n").unwrap();
+
+        let input = "{ \"STATEFP\": 27 }\n{ \"STATEFP\": 55 }\n{ \"STATEFP\": 19 }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            format!("d.STATEFP in @{}", path.display()),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            "{\"STATEFP\":27}\n{\"STATEFP\":19}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_exists_and_types() {
+        let input = "{ \"zip\": \"55401\", \"geometry\": null }\n{ \"zip\": 55402, \"geometry\": {} }\n{ \"geometry\": [] }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "missing(d.zip)".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!("{\"geometry\":[]}\n".as_bytes(), output.as_slice());
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "exists(d.zip) && d.geometry is null".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"geometry\":null,\"zip\":\"55401\"}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.zip is number || d.geometry is array".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"geometry\":{},\"zip\":55402}\n{\"geometry\":[]}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_any_all_len() {
+        let input = "{ \"tags\": [\"bridge\", \"road\"], \"coordinates\": [[0, 0], [1, 1], [2, 0], [0, 0]] }\n{ \"tags\": [\"road\"], \"coordinates\": [[0, 0], [1, 1]] }\n{ \"tags\": [] }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "any(d.tags, x == \"bridge\")".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"coordinates\":[[0,0],[1,1],[2,0],[0,0]],\"tags\":[\"bridge\",\"road\"]}\n"
+                .as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "all(d.tags, x == road)".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"coordinates\":[[0,0],[1,1]],\"tags\":[\"road\"]}\n{\"tags\":[]}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "len(d.coordinates) > 3 && any(d.coordinates, x[0] == 2)".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"coordinates\":[[0,0],[1,1],[2,0],[0,0]],\"tags\":[\"bridge\",\"road\"]}\n"
+                .as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_arithmetic() {
+        let input = "{ \"pop\": 3000, \"area\": 4, \"start\": 0, \"end\": 3600 }\n{ \"pop\": 1000, \"area\": 3, \"start\": 100, \"end\": 3600 }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.pop / d.area > 500".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"area\":4,\"end\":3600,\"pop\":3000,\"start\":0}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.end - d.start >= 3600 || (d.pop + 2) / d.area == 334".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"area\":4,\"end\":3600,\"pop\":3000,\"start\":0}\n{\"area\":3,\"end\":3600,\"pop\":1000,\"start\":100}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.start > d.area".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"area\":3,\"end\":3600,\"pop\":1000,\"start\":100}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_hyphenated_literals() {
+        let input = "{ \"date\": \"2020-01-01\", \"phone\": \"555-1234\", \"zip\": \"55401-1234\", \"n\": 5 }\n{ \"date\": 2018, \"phone\": 555, \"zip\": 55401, \"n\": 2 }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.date == 2020-01-01 && d.phone == 555-1234 && d.zip == 55401-1234".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"date\":\"2020-01-01\",\"n\":5,\"phone\":\"555-1234\",\"zip\":\"55401-1234\"}\n"
+                .as_bytes(),
+            output.as_slice()
+        );
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.n - 3 == 2 || d.n * 2 == 4".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"date\":\"2020-01-01\",\"n\":5,\"phone\":\"555-1234\",\"zip\":\"55401-1234\"}\n{\"date\":2018,\"n\":2,\"phone\":555,\"zip\":55401}\n"
+                .as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_bare_values_starting_with_selector_signifiers() {
+        let input = "{ \"state\": \"delaware\", \"size\": \"xl\" }\n{ \"state\": \"dakota\", \"size\": \"x\" }\n";
+
+        for (expression, expected) in &[
+            (
+                "d.state == delaware",
+                "{\"size\":\"xl\",\"state\":\"delaware\"}\n",
+            ),
+            ("d.size == xl", "{\"size\":\"xl\",\"state\":\"delaware\"}\n"),
+            ("d.size == x", "{\"size\":\"x\",\"state\":\"dakota\"}\n"),
+            (
+                "d.state != delaware && d.size == x",
+                "{\"size\":\"x\",\"state\":\"dakota\"}\n",
+            ),
+        ] {
+            let mut output = vec![];
+
+            ndjson_filter(expression.to_string(), &mut input.as_bytes(), &mut output).unwrap();
+
+            assert_eq!(expected.as_bytes(), output.as_slice(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_filter_colon_key() {
+        let input = "{ \"tags\": { \"addr:street\": \"Main\" } }\n{ \"tags\": {} }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "d.tags.addr:street == Main".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"tags\":{\"addr:street\":\"Main\"}}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_apply_arithmetic() {
+        assert_eq!(
+            apply_arithmetic(
+                OrderedNumber::PosInt(7),
+                &ArithmeticOperator::Div,
+                OrderedNumber::PosInt(2)
+            )
+            .unwrap(),
+            Value::from(3.5)
+        );
+
+        assert_eq!(
+            apply_arithmetic(
+                OrderedNumber::NegInt(-6),
+                &ArithmeticOperator::Div,
+                OrderedNumber::PosInt(2)
+            )
+            .unwrap(),
+            Value::from(-3)
+        );
+
+        assert!(apply_arithmetic(
+            OrderedNumber::PosInt(1),
+            &ArithmeticOperator::Div,
+            OrderedNumber::PosInt(0)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_select_multiple() {
+        let value: Value = serde_json::from_str(
+            "{\"features\": [{\"properties\": {\"name\": \"a\", \"inner\": {\"name\": \"b\"}}}, {\"properties\": {}}, {\"properties\": {\"name\": \"c\"}}]}",
+        )
+        .unwrap();
+
+        assert_eq!(
+            select_from_json_object(
+                value.clone(),
+                &[
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::Wildcard,
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Identifier("\"name\"".to_string()),
+                ]
+            )
+            .unwrap(),
+            serde_json::json!(["a", "c"])
+        );
+
+        assert_eq!(
+            select_from_json_object(
+                value.clone(),
+                &[Selector::Descendant("\"name\"".to_string())]
+            )
+            .unwrap(),
+            serde_json::json!(["a", "b", "c"])
+        );
+
+        assert_eq!(
+            select_from_json_object(
+                value.clone(),
+                &[
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::Slice(Some(-2), None),
+                    Selector::Identifier("\"properties\"".to_string()),
+                ]
+            )
+            .unwrap(),
+            serde_json::json!([{}, {"name": "c"}])
+        );
+
+        assert_eq!(
+            select_from_json_object(
+                value.clone(),
+                &[
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::IndexFromEnd(1),
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Identifier("\"name\"".to_string()),
+                ]
+            )
+            .unwrap(),
+            Value::from("c")
+        );
+
+        assert_eq!(
+            select_value(
+                &value,
+                &[
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::Wildcard,
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Identifier("\"name\"".to_string()),
+                ]
+            ),
+            Some(serde_json::json!(["a", "c"]))
+        );
+        assert_eq!(
+            select_value(
+                &value,
+                &[
+                    Selector::Identifier("\"features\"".to_string()),
+                    Selector::Index(1),
+                    Selector::Identifier("\"properties\"".to_string()),
+                    Selector::Identifier("\"name\"".to_string()),
+                ]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_filter_wildcard() {
+        let input = "{ \"tags\": [{ \"k\": \"bridge\" }, { \"k\": \"road\" }] }\n{ \"tags\": [{ \"k\": \"road\" }] }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "any(d.tags[*].k, x == bridge) && d.tags[-1].k == road".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"tags\":[{\"k\":\"bridge\"},{\"k\":\"road\"}]}\n".as_bytes(),
+            output.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_filter_multiple_values() {
+        let input = "{ \"items\": [{ \"x\": 1 }, {}] }\n{ \"items\": [{}] }\n{ \"items\": [] }\n";
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "exists(d.items[*].x)".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!("{\"items\":[{\"x\":1},{}]}\n".as_bytes(), output.as_slice());
+
+        let mut output = vec![];
+
+        ndjson_filter(
+            "missing(d..x) && len(d.items[*]) < 2".to_string(),
+            &mut input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "{\"items\":[{}]}\n{\"items\":[]}\n".as_bytes(),
+            output.as_slice()
+        );
+
+        for expression in &[
+            "d.items[*].x == 1",
+            "d.items[0:1] is array",
+            "d..x + 1 > 1",
+            "any(d.items, x..x == 1)",
+        ] {
+            assert!(
+                ndjson_filter(expression.to_string(), &mut input.as_bytes(), &mut vec![]).is_err(),
+                "{}",
+                expression
+            );
+        }
+    }
 }
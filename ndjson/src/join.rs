@@ -14,98 +14,671 @@
 * limitations under the License.
 */
 
-use crate::filter::select_from_json_object;
+use crate::{
+    external_sort::{external_sort, Key, DEFAULT_MEMORY_LIMIT},
+    filter::select_value,
+};
+use csv::ReaderBuilder;
 use ndjson_common::{
-    error::NdJsonSpatialError, json_selector_parser::Selector, ndjson::NdjsonReader,
+    error::NdJsonSpatialError, from::generic_split, infer_json::infer_json,
+    json_selector_parser::Selector, ndjson::NdjsonReader,
 };
 use ordered_float::OrderedFloat;
-use serde_json::{Number, Value};
+use serde_json::{Map, Number, Value};
 use std::{
+    cmp::Ordering,
     collections::BTreeMap,
-    io::{BufRead, Write},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    str::FromStr,
 };
+use tempfile::tempfile;
+
+/// Which records `join` writes besides the merged matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinType {
+    /// Only merged matches.
+    Inner,
+    /// Merged matches and unmatched stream records.
+    Left,
+    /// Merged matches and, at the end, unmatched reference records.
+    Right,
+    /// Merged matches, unmatched stream records and unmatched reference records.
+    Outer,
+    /// Stream records with at least one match, unmerged.
+    Semi,
+    /// Stream records without a match.
+    Anti,
+}
+
+/// How `join` writes records, and how reference records are merged into stream records.
+#[derive(Debug, Clone)]
+pub struct JoinOptions {
+    pub how: JoinType,
+    /// Prepended to every reference key that is not deep-merged.
+    pub prefix: String,
+    /// Appended to every reference key that is not deep-merged.
+    pub suffix: String,
+    /// Path of object keys in the stream record the reference record is merged into.
+    pub into: Vec<String>,
+    /// Merge objects present in both records recursively instead of replacing them.
+    pub deep_merge: bool,
+    pub matches: Matches,
+    pub strategy: JoinStrategy,
+    /// Bytes of records each input of a merge join buffers before spilling to disk.
+    pub memory_limit: usize,
+    pub ref_format: ReferenceFormat,
+    pub key: KeyOptions,
+}
+
+/// How join key values are normalized before they are compared.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyOptions {
+    /// Convert every key value to this type.
+    pub key_as: Option<KeyType>,
+    /// Trim whitespace from string values.
+    pub trim: bool,
+    /// Lowercase string values.
+    pub fold_case: bool,
+    /// Left-pad string values with zeros to this width.
+    pub zero_pad: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    String,
+    Number,
+}
+
+impl FromStr for KeyType {
+    type Err = NdJsonSpatialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(KeyType::String),
+            "number" => Ok(KeyType::Number),
+            other => Err(NdJsonSpatialError::Error(format!(
+                "--key-as must be one of 'string', 'number', not: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// How the reference file is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReferenceFormat {
+    Ndjson,
+    /// Csv with a header row and the given delimiter, inferring json types like `from-csv`.
+    Csv(u8),
+    /// A GeoJSON FeatureCollection, using each of its features.
+    FeatureCollection,
+}
+
+impl FromStr for ReferenceFormat {
+    type Err = NdJsonSpatialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" => Ok(ReferenceFormat::Ndjson),
+            "csv" => Ok(ReferenceFormat::Csv(b',')),
+            "tsv" => Ok(ReferenceFormat::Csv(b'\t')),
+            "geojson" => Ok(ReferenceFormat::FeatureCollection),
+            other => Err(NdJsonSpatialError::Error(format!(
+                "--ref-format must be one of 'ndjson', 'csv', 'tsv', 'geojson', not: {}",
+                other
+            ))),
+        }
+    }
+}
+
+impl ReferenceFormat {
+    /// Guesses the format from a file name's extension, defaulting to ndjson.
+    pub fn from_path(path: &str) -> Self {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("csv") => ReferenceFormat::Csv(b','),
+            Some("tsv") => ReferenceFormat::Csv(b'\t'),
+            Some("geojson") => ReferenceFormat::FeatureCollection,
+            _ => ReferenceFormat::Ndjson,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinStrategy {
+    /// Hold the reference records in memory, writing records in stream order.
+    Hash,
+    /// Sort both inputs by key on disk and merge them, writing records in key order.
+    Merge,
+}
+
+/// What `join` writes when a stream record matches several reference records.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matches {
+    /// One record per match.
+    Each,
+    /// One record merged with the first match only.
+    First,
+    /// One record with every match collected into an array at the path of object
+    /// keys, each match projected to the given fields or kept whole if there are none.
+    Collect(Vec<String>, Vec<(String, Vec<Selector>)>),
+}
+
+impl Default for JoinOptions {
+    fn default() -> Self {
+        JoinOptions {
+            how: JoinType::Inner,
+            prefix: String::new(),
+            suffix: String::new(),
+            into: vec![],
+            deep_merge: false,
+            matches: Matches::Each,
+            strategy: JoinStrategy::Hash,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            ref_format: ReferenceFormat::Ndjson,
+            key: KeyOptions::default(),
+        }
+    }
+}
+
+impl FromStr for JoinType {
+    type Err = NdJsonSpatialError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inner" => Ok(JoinType::Inner),
+            "left" => Ok(JoinType::Left),
+            "right" => Ok(JoinType::Right),
+            "outer" | "full" => Ok(JoinType::Outer),
+            "semi" => Ok(JoinType::Semi),
+            "anti" => Ok(JoinType::Anti),
+            other => Err(NdJsonSpatialError::Error(format!(
+                "--how must be one of 'inner', 'left', 'right', 'outer', 'semi', 'anti', not: {}",
+                other
+            ))),
+        }
+    }
+}
 
 pub fn join<B: BufRead, S: BufRead, O: Write>(
-    mut reference_reader: &mut B,
+    reference_reader: &mut B,
     reference_fields: Vec<Vec<Selector>>,
     stream_fields: Vec<Vec<Selector>>,
+    options: &JoinOptions,
     stream: S,
     mut out: O,
 ) -> Result<(), NdJsonSpatialError> {
-    let references: Vec<BTreeMap<OrderedValue, Vec<Value>>> = reference_fields
-        .into_iter()
-        .map(|reference_field| {
-            let mut references = BTreeMap::new();
-
-            for g in NdjsonReader::new(&mut reference_reader).flatten() {
-                if g.is_object() {
-                    match select_from_json_object(g.clone(), &reference_field) {
-                        Ok(field_value) => {
-                            let field_value: OrderedValue = field_value.into();
-
-                            references
-                                .entry(field_value)
-                                .and_modify(|v: &mut Vec<Value>| v.push(g.clone()))
-                                .or_insert_with(|| vec![g]);
-                        }
-                        Err(e) => {
-                            writeln!(
-                                std::io::stderr(),
-                                "Unable to select from reference object: {:?}",
-                                e
-                            )
-                            .expect("Unable to write to stderr");
-                        }
-                    }
-                }
+    if reference_fields.len() != stream_fields.len() {
+        return Err(NdJsonSpatialError::Error(format!(
+            "Expected as many reference fields as stream fields, found {} and {}",
+            reference_fields.len(),
+            stream_fields.len()
+        )));
+    }
+
+    if options.strategy == JoinStrategy::Merge {
+        return merge_join(
+            reference_reader,
+            reference_fields,
+            stream_fields,
+            options,
+            stream,
+            out,
+        );
+    }
+
+    let reference_values: Vec<Value> =
+        reference_records(reference_reader, options.ref_format)?.collect();
+
+    let mut references: BTreeMap<Vec<OrderedValue>, Vec<usize>> = BTreeMap::new();
+
+    for (index, g) in reference_values.iter().enumerate() {
+        match join_key(g, &reference_fields, &options.key) {
+            Ok(Some(key)) => references.entry(key).or_default().push(index),
+            Ok(None) => {}
+            Err(e) => {
+                writeln!(
+                    std::io::stderr(),
+                    "Unable to select from reference object: {:?}",
+                    e
+                )
+                .expect("Unable to write to stderr");
             }
-            references
-        })
-        .collect();
+        }
+    }
+
+    let mut reference_matched = vec![false; reference_values.len()];
+    let mut unmatched = Unmatched::default();
 
     for val in NdjsonReader::new(stream) {
-        stream_fields
+        let value = match val {
+            Ok(value) => value,
+            Err(e) => {
+                writeln!(std::io::stderr(), "Error reading: {:?}", e)
+                    .expect("Unable to write to stderr");
+                continue;
+            }
+        };
+
+        let matches = match join_key(&value, &stream_fields, &options.key) {
+            Ok(Some(key)) => references.get(&key).cloned().unwrap_or_default(),
+            _ => {
+                unmatched.keyless += 1;
+                vec![]
+            }
+        };
+        if matches.is_empty() {
+            unmatched.records += 1;
+        }
+
+        for index in &matches {
+            reference_matched[*index] = true;
+        }
+
+        let matches = matches
             .iter()
-            .map(|identifiers| match val.clone() {
-                Ok(value) => select_from_json_object(value, identifiers).ok(),
-                Err(e) => {
-                    writeln!(std::io::stderr(), "Error reading: {:?}", e)
-                        .expect("Unable to write to stderr");
-                    None
+            .map(|index| &reference_values[*index])
+            .collect::<Vec<&Value>>();
+
+        write_joined(&mut out, value, &matches, options);
+    }
+
+    if let JoinType::Right | JoinType::Outer = options.how {
+        for (reference, matched) in reference_values.iter().zip(reference_matched) {
+            if !matched {
+                writeln!(out, "{}", reference).expect("Unable to write to stdout");
+            }
+        }
+    }
+    unmatched.report();
+    Ok(())
+}
+
+/// Sorts both inputs by join key, spilling to temporary files past `memory_limit`,
+/// and walks them together so only the reference records of one key are in memory.
+fn merge_join<B: BufRead, S: BufRead, O: Write>(
+    reference_reader: &mut B,
+    reference_fields: Vec<Vec<Selector>>,
+    stream_fields: Vec<Vec<Selector>>,
+    options: &JoinOptions,
+    stream: S,
+    mut out: O,
+) -> Result<(), NdJsonSpatialError> {
+    // Records without a usable key get the empty key, which sorts first and matches nothing.
+    let keyed = |fields: &[Vec<Selector>], value: Value| {
+        let key = join_key(&value, fields, &options.key)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        Ok((key, value))
+    };
+    let compare = |a: &Key, b: &Key| a.cmp(b);
+
+    let references = external_sort(
+        reference_records(reference_reader, options.ref_format)?
+            .map(|g| keyed(&reference_fields, g)),
+        &compare,
+        options.memory_limit,
+    )?;
+    let stream = external_sort(
+        NdjsonReader::new(stream).filter_map(|val| match val {
+            Ok(value) => Some(keyed(&stream_fields, value)),
+            Err(e) => {
+                writeln!(std::io::stderr(), "Error reading: {:?}", e)
+                    .expect("Unable to write to stderr");
+                None
+            }
+        }),
+        &compare,
+        options.memory_limit,
+    )?;
+
+    let keep_unmatched_references = matches!(options.how, JoinType::Right | JoinType::Outer);
+    let mut references = references.peekable();
+    let mut group_key: Key = vec![];
+    let mut group: Vec<Value> = vec![];
+    let mut group_matched = false;
+
+    let flush_group = |out: &mut O, group: &mut Vec<Value>, matched: bool| {
+        if keep_unmatched_references && !matched {
+            for reference in group.iter() {
+                writeln!(out, "{}", reference).expect("Unable to write to stdout");
+            }
+        }
+        group.clear();
+    };
+
+    let mut unmatched = Unmatched::default();
+
+    for record in stream {
+        let (key, value) = record?;
+
+        if key.is_empty() {
+            unmatched.keyless += 1;
+            unmatched.records += 1;
+            write_joined(&mut out, value, &[], options);
+            continue;
+        }
+
+        if key != group_key || group_key.is_empty() {
+            flush_group(&mut out, &mut group, group_matched);
+            group_matched = false;
+
+            loop {
+                match references.peek() {
+                    Some(Ok((reference_key, _))) if *reference_key <= key => {}
+                    Some(Ok(_)) | None => break,
+                    Some(Err(_)) => {
+                        return Err(references
+                            .next()
+                            .and_then(|r| r.err())
+                            .expect("peeked an error"))
+                    }
                 }
-            })
-            .zip(references.iter())
-            .for_each(|(v, references)| {
-                let v: Option<OrderedValue> = v.map(|v| v.into());
-
-                if let Some(v) = v {
-                    if !matches!(
-                        v,
-                        OrderedValue::Array(_) | OrderedValue::Object(_) | OrderedValue::Null
-                    ) {
-                        if let Some(g) = references.get(&v) {
-                            for value in g {
-                                if let (Value::Object(s), Ok(Value::Object(mut o))) =
-                                    (value, val.clone())
-                                {
-                                    for (k, v) in s.into_iter() {
-                                        o.insert(k.to_owned(), v.to_owned());
-                                    }
-
-                                    let value = Value::from(o);
-
-                                    writeln!(out, "{}", value).expect("Unable to write to stdout");
-                                }
-                            }
+                let (reference_key, reference) = references.next().expect("peeked")?;
+                if reference_key == key {
+                    group.push(reference);
+                } else if keep_unmatched_references {
+                    writeln!(out, "{}", reference).expect("Unable to write to stdout");
+                }
+            }
+            group_key = key;
+        }
+
+        group_matched |= !group.is_empty();
+        if group.is_empty() {
+            unmatched.records += 1;
+        }
+        write_joined(&mut out, value, &group.iter().collect::<Vec<_>>(), options);
+    }
+
+    flush_group(&mut out, &mut group, group_matched);
+
+    if keep_unmatched_references {
+        for reference in references {
+            writeln!(out, "{}", reference?.1).expect("Unable to write to stdout");
+        }
+    }
+    unmatched.report();
+    Ok(())
+}
+
+/// Counts of stream records that matched no reference record.
+#[derive(Default)]
+struct Unmatched {
+    records: usize,
+    /// Those without a usable key, because a field was missing, null, an array or an object.
+    keyless: usize,
+}
+
+impl Unmatched {
+    fn report(&self) {
+        if self.records > 0 {
+            writeln!(
+                std::io::stderr(),
+                "{} stream records did not match a reference key, {} of them had no usable key",
+                self.records,
+                self.keyless
+            )
+            .expect("Unable to write to stderr");
+        }
+    }
+}
+
+/// The json objects of the reference file.
+fn reference_records<'a, B: BufRead>(
+    reference_reader: &'a mut B,
+    format: ReferenceFormat,
+) -> Result<Box<dyn Iterator<Item = Value> + 'a>, NdJsonSpatialError> {
+    match format {
+        ReferenceFormat::Ndjson => {
+            let mut records = NdjsonReader::new(reference_reader)
+                .flatten()
+                .filter(|g| g.is_object())
+                .peekable();
+
+            // A FeatureCollection saved as .json would otherwise be a single record
+            // without the join fields, silently matching nothing.
+            if records
+                .peek()
+                .and_then(|g| g.get("type"))
+                .map(|t| t == "FeatureCollection")
+                .unwrap_or(false)
+            {
+                return Err(NdJsonSpatialError::Error(
+                    "The reference file is a GeoJSON FeatureCollection, name it .geojson or use --ref-format geojson".to_string(),
+                ));
+            }
+
+            Ok(Box::new(records))
+        }
+        ReferenceFormat::Csv(delimiter) => {
+            let mut reader = ReaderBuilder::new()
+                .delimiter(delimiter)
+                .from_reader(reference_reader);
+
+            let headers = reader.headers().cloned().map_err(|e| {
+                NdJsonSpatialError::Error(format!("During read from csv headers: {}", e))
+            })?;
+
+            Ok(Box::new(reader.into_records().filter_map(move |row| {
+                match row {
+                    Ok(row) => Some(Value::Object(
+                        headers
+                            .iter()
+                            .zip(row.iter())
+                            .map(|(head, item)| (head.to_string(), infer_json(item)))
+                            .collect(),
+                    )),
+                    Err(e) => {
+                        writeln!(std::io::stderr(), "{}", e).expect("Unable to write to stderr");
+                        None
+                    }
+                }
+            })))
+        }
+        ReferenceFormat::FeatureCollection => {
+            let io_error = |e: std::io::Error| {
+                NdJsonSpatialError::Error(format!("Unable to use temporary file: {}", e))
+            };
+
+            let mut features = tempfile().map_err(io_error)?;
+            generic_split(
+                reference_reader,
+                &mut features,
+                vec![Selector::Identifier("\"features\"".to_string())],
+            )?;
+            features.seek(SeekFrom::Start(0)).map_err(io_error)?;
+
+            Ok(Box::new(
+                NdjsonReader::new(BufReader::new(features))
+                    .flatten()
+                    .filter(|g| g.is_object()),
+            ))
+        }
+    }
+}
+
+/// Writes what `options` asks for given a stream record and its matching reference records.
+fn write_joined<O: Write>(out: &mut O, value: Value, matches: &[&Value], options: &JoinOptions) {
+    match options.how {
+        JoinType::Semi if !matches.is_empty() => {
+            writeln!(out, "{}", value).expect("Unable to write to stdout");
+        }
+        JoinType::Anti if matches.is_empty() => {
+            writeln!(out, "{}", value).expect("Unable to write to stdout");
+        }
+        JoinType::Semi | JoinType::Anti => {}
+        JoinType::Inner | JoinType::Left | JoinType::Right | JoinType::Outer => {
+            let keep_unmatched = matches!(options.how, JoinType::Left | JoinType::Outer);
+
+            match (&options.matches, value) {
+                (Matches::Collect(path, fields), Value::Object(mut o))
+                    if !matches.is_empty() || keep_unmatched =>
+                {
+                    let collected = matches
+                        .iter()
+                        .map(|reference| project(reference, fields))
+                        .collect();
+                    if let Some((last, parents)) = path.split_last() {
+                        object_at(&mut o, parents).insert(last.clone(), Value::Array(collected));
+                    }
+                    writeln!(out, "{}", Value::from(o)).expect("Unable to write to stdout");
+                }
+                (_, value) if matches.is_empty() && keep_unmatched => {
+                    writeln!(out, "{}", value).expect("Unable to write to stdout");
+                }
+                (Matches::Each, Value::Object(o)) | (Matches::First, Value::Object(o)) => {
+                    let count = if options.matches == Matches::First {
+                        1
+                    } else {
+                        matches.len()
+                    };
+                    for reference in matches.iter().take(count) {
+                        if let Value::Object(s) = reference {
+                            let mut o = o.clone();
+                            merge_object(object_at(&mut o, &options.into), s, options);
+
+                            let value = Value::from(o);
+
+                            writeln!(out, "{}", value).expect("Unable to write to stdout");
                         }
                     }
                 }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The object at `path`, replacing missing or non-object values along the way with empty objects.
+fn object_at<'a>(
+    object: &'a mut Map<String, Value>,
+    path: &[String],
+) -> &'a mut Map<String, Value> {
+    let mut target = object;
+    for key in path {
+        let entry = target
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        target = entry.as_object_mut().expect("entry is an object");
+    }
+    target
+}
+
+fn merge_object(
+    target: &mut Map<String, Value>,
+    reference: &Map<String, Value>,
+    options: &JoinOptions,
+) {
+    for (k, v) in reference {
+        if options.deep_merge {
+            if let (Some(Value::Object(t)), Value::Object(r)) = (target.get_mut(k), v) {
+                merge_object(t, r, options);
+                continue;
+            }
+        }
+        target.insert(
+            format!("{}{}{}", options.prefix, k, options.suffix),
+            v.clone(),
+        );
+    }
+}
+
+fn project(reference: &Value, fields: &[(String, Vec<Selector>)]) -> Value {
+    if fields.is_empty() {
+        return reference.clone();
+    }
+    Value::Object(
+        fields
+            .iter()
+            .map(|(name, selectors)| {
+                (
+                    name.clone(),
+                    select_value(reference, selectors).unwrap_or(Value::Null),
+                )
             })
+            .collect(),
+    )
+}
+
+/// The normalized values of every field, which must all be present and neither null,
+/// arrays nor objects.
+fn join_key(
+    value: &Value,
+    fields: &[Vec<Selector>],
+    options: &KeyOptions,
+) -> Result<Option<Vec<OrderedValue>>, NdJsonSpatialError> {
+    let mut key = Vec::with_capacity(fields.len());
+    for field in fields {
+        let selected = select_value(value, field).ok_or_else(|| {
+            NdJsonSpatialError::Error("Object has no value for a join field".to_string())
+        })?;
+        match selected.into() {
+            OrderedValue::Array(_) | OrderedValue::Object(_) | OrderedValue::Null => {
+                return Ok(None)
+            }
+            v => match options.normalize(v) {
+                Some(v) => key.push(v),
+                None => return Ok(None),
+            },
+        }
     }
-    Ok(())
+    Ok(Some(key))
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+impl KeyOptions {
+    /// Applies `key_as`, then trimming, case folding and padding to string values. None
+    /// when the value cannot be converted, such as a non-numeric string with `--key-as number`.
+    fn normalize(&self, value: OrderedValue) -> Option<OrderedValue> {
+        let value = match (self.key_as, value) {
+            (Some(KeyType::String), OrderedValue::Number(n)) => {
+                OrderedValue::String(Value::from(n.canonical()).to_string())
+            }
+            (Some(KeyType::String), OrderedValue::Bool(b)) => OrderedValue::String(b.to_string()),
+            (_, v) => v,
+        };
+
+        let value = match value {
+            OrderedValue::String(s) => {
+                let s = if self.trim { s.trim().to_string() } else { s };
+                let s = if self.fold_case { s.to_lowercase() } else { s };
+                match (self.zero_pad, s.strip_prefix('-')) {
+                    // The sign goes before the padding, as with `{:05}` for numbers.
+                    (Some(width), Some(digits)) => OrderedValue::String(format!(
+                        "-{:0>width$}",
+                        digits,
+                        width = width.saturating_sub(1)
+                    )),
+                    (Some(width), None) => {
+                        OrderedValue::String(format!("{:0>width$}", s, width = width))
+                    }
+                    (None, _) => OrderedValue::String(s),
+                }
+            }
+            v => v,
+        };
+
+        match (self.key_as, value) {
+            (Some(KeyType::Number), OrderedValue::String(s)) => {
+                OrderedNumber::parse(s.trim()).map(OrderedValue::Number)
+            }
+            (Some(KeyType::Number), OrderedValue::Number(n)) => {
+                Some(OrderedValue::Number(n.canonical()))
+            }
+            (Some(KeyType::Number), _) => None,
+            (_, v) => Some(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum OrderedValue {
     String(String),
     Bool(bool),
@@ -115,13 +688,75 @@ pub enum OrderedValue {
     Object(BTreeMap<String, OrderedValue>),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub enum OrderedNumber {
     PosInt(u64),
     NegInt(i64),
     Float(OrderedFloat<f64>),
 }
 
+impl OrderedNumber {
+    pub fn to_f64(self) -> f64 {
+        match self {
+            OrderedNumber::PosInt(v) => v as f64,
+            OrderedNumber::NegInt(v) => v as f64,
+            OrderedNumber::Float(v) => v.0,
+        }
+    }
+
+    /// Parses an integer or finite float, whole floats as integers.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Ok(v) = s.parse::<u64>() {
+            Some(OrderedNumber::PosInt(v))
+        } else if let Ok(v) = s.parse::<i64>() {
+            Some(OrderedNumber::NegInt(v))
+        } else {
+            match s.parse::<f64>() {
+                Ok(v) if v.is_finite() => Some(OrderedNumber::Float(OrderedFloat(v)).canonical()),
+                _ => None,
+            }
+        }
+    }
+
+    /// Whole floats as integers, so that `27.0` equals `27`.
+    pub fn canonical(self) -> Self {
+        match self {
+            OrderedNumber::Float(v)
+                if v.0.fract() == 0.0 && v.0.abs() < 9_007_199_254_740_992.0 =>
+            {
+                if v.0 >= 0.0 {
+                    OrderedNumber::PosInt(v.0 as u64)
+                } else {
+                    OrderedNumber::NegInt(v.0 as i64)
+                }
+            }
+            n => n,
+        }
+    }
+
+    /// Compares by numeric value, promoting to float when either side is a float,
+    /// unlike the derived `Ord` which orders by variant first.
+    pub fn numeric_cmp(&self, other: &OrderedNumber) -> Option<Ordering> {
+        match (self, other) {
+            (OrderedNumber::PosInt(s), OrderedNumber::PosInt(o)) => Some(s.cmp(o)),
+            (OrderedNumber::NegInt(s), OrderedNumber::NegInt(o)) => Some(s.cmp(o)),
+            (OrderedNumber::PosInt(_), OrderedNumber::NegInt(_)) => Some(Ordering::Greater),
+            (OrderedNumber::NegInt(_), OrderedNumber::PosInt(_)) => Some(Ordering::Less),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl From<OrderedNumber> for Value {
+    fn from(other: OrderedNumber) -> Self {
+        match other {
+            OrderedNumber::PosInt(v) => Value::from(v),
+            OrderedNumber::NegInt(v) => Value::from(v),
+            OrderedNumber::Float(v) => Value::from(v.0),
+        }
+    }
+}
+
 impl From<Number> for OrderedNumber {
     fn from(other: Number) -> Self {
         if let Some(v) = other.as_u64() {
@@ -155,6 +790,21 @@ impl From<Value> for OrderedValue {
     }
 }
 
+impl From<OrderedValue> for Value {
+    fn from(other: OrderedValue) -> Self {
+        match other {
+            OrderedValue::String(s) => Value::String(s),
+            OrderedValue::Null => Value::Null,
+            OrderedValue::Number(n) => n.into(),
+            OrderedValue::Bool(b) => Value::Bool(b),
+            OrderedValue::Array(arr) => Value::Array(arr.into_iter().map(|v| v.into()).collect()),
+            OrderedValue::Object(obj) => {
+                Value::Object(obj.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,6 +821,7 @@ mod tests {
             &mut file,
             vec![vec![Selector::Identifier("foo".to_owned())]],
             vec![vec![Selector::Identifier("foo".to_owned())]],
+            &JoinOptions::default(),
             &mut input,
             &mut output,
         )
@@ -194,6 +845,7 @@ mod tests {
             &mut ref_file,
             vec![vec![Selector::Identifier("foo".to_owned())]],
             vec![vec![Selector::Identifier("foo".to_owned())]],
+            &JoinOptions::default(),
             &mut input,
             &mut output,
         )
@@ -225,6 +877,7 @@ mod tests {
                 vec![Selector::Identifier("foo".to_owned())],
                 vec![Selector::Identifier("bub".to_owned())],
             ],
+            &JoinOptions::default(),
             &mut input,
             &mut output,
         )
@@ -255,6 +908,7 @@ mod tests {
                 vec![Selector::Identifier("state".to_owned())],
                 vec![Selector::Identifier("county".to_owned())],
             ],
+            &JoinOptions::default(),
             &mut input,
             &mut output,
         )
@@ -266,6 +920,381 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_join_composite_key() {
+        let input = "{\"state\":\"MN\",\"county\":\"Hennepin\"}\n{\"state\":\"WI\",\"county\":\"Ramsey\"}\n";
+
+        let ref_file = "{\"state\":\"MN\",\"county\":\"Ramsey\",\"pop\":1}\n{\"state\":\"MN\",\"county\":\"Hennepin\",\"pop\":2}\n";
+
+        let fields = || {
+            vec![
+                vec![Selector::Identifier("state".to_owned())],
+                vec![Selector::Identifier("county".to_owned())],
+            ]
+        };
+
+        let mut output = vec![];
+
+        join(
+            &mut ref_file.as_bytes(),
+            fields(),
+            fields(),
+            &JoinOptions {
+                how: JoinType::Left,
+                ..JoinOptions::default()
+            },
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"county\":\"Hennepin\",\"pop\":2,\"state\":\"MN\"}\n{\"county\":\"Ramsey\",\"state\":\"WI\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_join_merge_options() {
+        let input = "{\"type\":\"Feature\",\"properties\":{\"id\":1,\"name\":\"a\"}}\n";
+
+        let ref_file = "{\"type\":\"Row\",\"properties\":{\"id\":1,\"pop\":5}}\n";
+
+        let run = |options: JoinOptions| {
+            let mut output = vec![];
+            join(
+                &mut ref_file.as_bytes(),
+                vec![vec![
+                    Selector::Identifier("properties".to_owned()),
+                    Selector::Identifier("id".to_owned()),
+                ]],
+                vec![vec![
+                    Selector::Identifier("properties".to_owned()),
+                    Selector::Identifier("id".to_owned()),
+                ]],
+                &options,
+                input.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            run(JoinOptions {
+                deep_merge: true,
+                prefix: "ref_".to_owned(),
+                ..JoinOptions::default()
+            }),
+            "{\"properties\":{\"id\":1,\"name\":\"a\",\"ref_id\":1,\"ref_pop\":5},\"ref_type\":\"Row\",\"type\":\"Feature\"}\n"
+        );
+
+        assert_eq!(
+            run(JoinOptions {
+                into: vec!["properties".to_owned(), "ref".to_owned()],
+                ..JoinOptions::default()
+            }),
+            "{\"properties\":{\"id\":1,\"name\":\"a\",\"ref\":{\"properties\":{\"id\":1,\"pop\":5},\"type\":\"Row\"}},\"type\":\"Feature\"}\n"
+        );
+
+        assert_eq!(
+            run(JoinOptions {
+                suffix: "_ref".to_owned(),
+                ..JoinOptions::default()
+            }),
+            "{\"properties\":{\"id\":1,\"name\":\"a\"},\"properties_ref\":{\"id\":1,\"pop\":5},\"type\":\"Feature\",\"type_ref\":\"Row\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_join_matches() {
+        let input = "{\"id\":1}\n{\"id\":2}\n";
+
+        let ref_file = "{\"id\":1,\"name\":\"a\",\"pop\":3}\n{\"id\":1,\"name\":\"b\",\"pop\":4}\n";
+
+        let run = |options: JoinOptions| {
+            let mut output = vec![];
+            join(
+                &mut ref_file.as_bytes(),
+                vec![vec![Selector::Identifier("id".to_owned())]],
+                vec![vec![Selector::Identifier("id".to_owned())]],
+                &options,
+                input.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            run(JoinOptions {
+                matches: Matches::First,
+                ..JoinOptions::default()
+            }),
+            "{\"id\":1,\"name\":\"a\",\"pop\":3}\n"
+        );
+
+        assert_eq!(
+            run(JoinOptions {
+                how: JoinType::Left,
+                matches: Matches::Collect(
+                    vec!["properties".to_owned(), "matches".to_owned()],
+                    vec![(
+                        "name".to_owned(),
+                        vec![Selector::Identifier("name".to_owned())]
+                    )]
+                ),
+                ..JoinOptions::default()
+            }),
+            "{\"id\":1,\"properties\":{\"matches\":[{\"name\":\"a\"},{\"name\":\"b\"}]}}\n{\"id\":2,\"properties\":{\"matches\":[]}}\n"
+        );
+    }
+
+    #[test]
+    fn test_join_csv_reference() {
+        let input =
+            "{\"fips\":27053,\"name\":\"Hennepin\"}\n{\"fips\":27123,\"name\":\"Ramsey\"}\n";
+
+        let ref_file = "fips\tpopulation\tstate\n27053\t1281565\tMN\n27123\t552352\tMN\n";
+
+        let mut output = vec![];
+
+        join(
+            &mut ref_file.as_bytes(),
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            &JoinOptions {
+                ref_format: ReferenceFormat::Csv(b'\t'),
+                ..JoinOptions::default()
+            },
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"fips\":27053,\"name\":\"Hennepin\",\"population\":1281565,\"state\":\"MN\"}\n{\"fips\":27123,\"name\":\"Ramsey\",\"population\":552352,\"state\":\"MN\"}\n"
+        );
+
+        assert_eq!(
+            ReferenceFormat::from_path("data/Counties.GeoJSON"),
+            ReferenceFormat::FeatureCollection
+        );
+        assert_eq!(
+            ReferenceFormat::from_path("counties.ndjson"),
+            ReferenceFormat::Ndjson
+        );
+    }
+
+    #[test]
+    fn test_join_ndjson_reference_named_json() {
+        let input = "{\"fips\":27053}\n";
+
+        let ref_file = "{\"fips\":27053,\"state\":\"MN\"}\n";
+
+        let mut output = vec![];
+
+        join(
+            &mut ref_file.as_bytes(),
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            &JoinOptions {
+                ref_format: ReferenceFormat::from_path("population.json"),
+                ..JoinOptions::default()
+            },
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"fips\":27053,\"state\":\"MN\"}\n"
+        );
+
+        let collection = "{\"type\":\"FeatureCollection\",\"features\":[{\"type\":\"Feature\",\"properties\":{\"fips\":27053},\"geometry\":null}]}\n";
+
+        assert!(join(
+            &mut collection.as_bytes(),
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            vec![vec![Selector::Identifier("fips".to_owned())]],
+            &JoinOptions {
+                ref_format: ReferenceFormat::from_path("counties.json"),
+                ..JoinOptions::default()
+            },
+            input.as_bytes(),
+            &mut vec![],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_join_key_options() {
+        let input = "{\"fips\":\"027\"}\n{\"fips\":\" 27.0 \"}\n{\"fips\":\"x\"}\n";
+
+        let ref_file = "{\"fips\":27,\"n\":1}\n";
+
+        let run = |key| {
+            let mut output = vec![];
+            join(
+                &mut ref_file.as_bytes(),
+                vec![vec![Selector::Identifier("fips".to_owned())]],
+                vec![vec![Selector::Identifier("fips".to_owned())]],
+                &JoinOptions {
+                    key,
+                    ..JoinOptions::default()
+                },
+                input.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(run(KeyOptions::default()), "");
+
+        assert_eq!(
+            run(KeyOptions {
+                key_as: Some(KeyType::Number),
+                ..KeyOptions::default()
+            }),
+            "{\"fips\":27,\"n\":1}\n{\"fips\":27,\"n\":1}\n"
+        );
+
+        assert_eq!(
+            run(KeyOptions {
+                key_as: Some(KeyType::String),
+                zero_pad: Some(3),
+                ..KeyOptions::default()
+            }),
+            "{\"fips\":27,\"n\":1}\n"
+        );
+
+        let key = KeyOptions {
+            trim: true,
+            fold_case: true,
+            ..KeyOptions::default()
+        };
+        assert_eq!(
+            key.normalize(OrderedValue::String(" AbC ".to_string())),
+            Some(OrderedValue::String("abc".to_string()))
+        );
+
+        let key = KeyOptions {
+            key_as: Some(KeyType::String),
+            zero_pad: Some(4),
+            ..KeyOptions::default()
+        };
+        assert_eq!(
+            key.normalize(OrderedValue::Number(OrderedNumber::NegInt(-5))),
+            Some(OrderedValue::String("-005".to_string()))
+        );
+        assert_eq!(
+            key.normalize(OrderedValue::String("5".to_string())),
+            Some(OrderedValue::String("0005".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merge_join() {
+        let input =
+            "{\"foo\":3}\n{\"foo\":1,\"bar\":1}\n{\"bar\":2}\n{\"foo\":1,\"bar\":3}\n{\"foo\":5}\n";
+
+        let ref_file = "{\"foo\":4,\"baz\":0}\n{\"foo\":1,\"baz\":1}\n{\"foo\":3,\"baz\":3}\n{\"foo\":1,\"baz\":2}\n";
+
+        let run = |how, strategy, memory_limit| {
+            let mut output = vec![];
+            join(
+                &mut ref_file.as_bytes(),
+                vec![vec![Selector::Identifier("foo".to_owned())]],
+                vec![vec![Selector::Identifier("foo".to_owned())]],
+                &JoinOptions {
+                    how,
+                    strategy,
+                    memory_limit,
+                    ..JoinOptions::default()
+                },
+                input.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            let mut lines = String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|l| l.to_owned())
+                .collect::<Vec<_>>();
+            lines.sort();
+            lines
+        };
+
+        for &how in &[
+            JoinType::Inner,
+            JoinType::Left,
+            JoinType::Right,
+            JoinType::Outer,
+            JoinType::Semi,
+            JoinType::Anti,
+        ] {
+            let expected = run(how, JoinStrategy::Hash, 0);
+            assert_eq!(run(how, JoinStrategy::Merge, 1024), expected);
+            assert_eq!(run(how, JoinStrategy::Merge, 1), expected);
+        }
+
+        assert_eq!(
+            run(JoinType::Outer, JoinStrategy::Merge, 1),
+            vec![
+                "{\"bar\":1,\"baz\":1,\"foo\":1}",
+                "{\"bar\":1,\"baz\":2,\"foo\":1}",
+                "{\"bar\":2}",
+                "{\"bar\":3,\"baz\":1,\"foo\":1}",
+                "{\"bar\":3,\"baz\":2,\"foo\":1}",
+                "{\"baz\":0,\"foo\":4}",
+                "{\"baz\":3,\"foo\":3}",
+                "{\"foo\":5}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_how() {
+        let input = "{\"foo\":1,\"bar\":2}\n{\"foo\":5,\"bar\":6}\n";
+
+        let ref_file = "{\"baz\":3,\"foo\":1}\n{\"baz\":4,\"foo\":7}\n";
+
+        let run = |how| {
+            let mut output = vec![];
+            join(
+                &mut ref_file.as_bytes(),
+                vec![vec![Selector::Identifier("foo".to_owned())]],
+                vec![vec![Selector::Identifier("foo".to_owned())]],
+                &JoinOptions {
+                    how,
+                    ..JoinOptions::default()
+                },
+                input.as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            run(JoinType::Left),
+            "{\"bar\":2,\"baz\":3,\"foo\":1}\n{\"bar\":6,\"foo\":5}\n"
+        );
+        assert_eq!(
+            run(JoinType::Right),
+            "{\"bar\":2,\"baz\":3,\"foo\":1}\n{\"baz\":4,\"foo\":7}\n"
+        );
+        assert_eq!(
+            run(JoinType::Outer),
+            "{\"bar\":2,\"baz\":3,\"foo\":1}\n{\"bar\":6,\"foo\":5}\n{\"baz\":4,\"foo\":7}\n"
+        );
+        assert_eq!(run(JoinType::Semi), "{\"bar\":2,\"foo\":1}\n");
+        assert_eq!(run(JoinType::Anti), "{\"bar\":6,\"foo\":5}\n");
+    }
+
     #[test]
     fn test_ordered_number() {
         let val: OrderedNumber = serde_json::Number::from_f64(4.5).unwrap().into();
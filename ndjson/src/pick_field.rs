@@ -14,12 +14,15 @@
  * limitations under the License.
  */
 
-use crate::filter::select_from_json_object;
+use crate::filter::{select_from_json_object, select_value};
 use ndjson_common::{
     error::NdJsonSpatialError, json_selector_parser::Selector, ndjson::NdjsonReader,
 };
-use serde_json::ser::to_string;
-use std::io::{BufRead, Write};
+use serde_json::{ser::to_string, Map, Value};
+use std::{
+    collections::HashSet,
+    io::{BufRead, Write},
+};
 
 pub fn pick_field<I: BufRead, O: Write>(
     identifiers: Vec<Selector>,
@@ -43,6 +46,94 @@ pub fn pick_field<I: BufRead, O: Write>(
     Ok(())
 }
 
+/// A selector to pick along with the name it is written under.
+pub struct PickedField {
+    pub name: String,
+    pub selectors: Vec<Selector>,
+}
+
+impl PickedField {
+    /// Names the field after the last key of the selector, or the whole expression.
+    pub fn new(selectors: Vec<Selector>, name: Option<String>, expression: &str) -> Self {
+        let name = name.unwrap_or_else(|| {
+            selectors
+                .iter()
+                .rev()
+                .find_map(|s| match s {
+                    Selector::Identifier(ident) | Selector::Descendant(ident) => Some(
+                        ident
+                            .strip_prefix('"')
+                            .and_then(|s| s.strip_suffix('"'))
+                            .unwrap_or(ident)
+                            .to_owned(),
+                    ),
+                    _ => None,
+                })
+                .unwrap_or_else(|| expression.to_owned())
+        });
+        PickedField { name, selectors }
+    }
+}
+
+/// Fails if two fields would be written under the same name, which would drop one of them.
+pub fn check_unique_names<'a, I: IntoIterator<Item = &'a str>>(
+    names: I,
+) -> Result<(), NdJsonSpatialError> {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(NdJsonSpatialError::Error(format!(
+                "More than one field is named {}, name them with <selector>:<name>",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Picks several fields from each object, writing them as an object keyed by
+/// name or, with `as_array`, as an array in the order given. Missing fields are null.
+pub fn pick_fields<I: BufRead, O: Write>(
+    fields: Vec<PickedField>,
+    as_array: bool,
+    input: &mut I,
+    mut output: O,
+) -> Result<(), NdJsonSpatialError> {
+    if !as_array {
+        check_unique_names(fields.iter().map(|f| f.name.as_str()))?;
+    }
+
+    for (i, value) in NdjsonReader::new(input).enumerate() {
+        let v = value?;
+        let picked = fields
+            .iter()
+            .map(|f| select_value(&v, &f.selectors).unwrap_or(Value::Null));
+
+        let value = if as_array {
+            Value::Array(picked.collect())
+        } else {
+            Value::Object(
+                fields
+                    .iter()
+                    .map(|f| f.name.clone())
+                    .zip(picked)
+                    .collect::<Map<String, Value>>(),
+            )
+        };
+
+        match to_string(&value) {
+            Ok(s) => {
+                writeln!(&mut output, "{}", s).expect("Unable to write to stdout");
+            }
+            Err(e) => {
+                writeln!(std::io::stderr(), "Error Serializing (input {}): {}", i, e)
+                    .expect("Unable to write to stderr");
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,4 +153,61 @@ mod tests {
 
         assert_eq!(output, "1\n2\n5\n".as_bytes().to_owned());
     }
+
+    #[test]
+    fn test_pick_fields() {
+        let input = "{\"a\":{\"b\":1},\"c\":\"x\"}\n{\"c\":\"y\"}\n";
+
+        let fields = || {
+            vec![
+                PickedField::new(
+                    vec![
+                        Selector::Identifier("\"a\"".to_owned()),
+                        Selector::Identifier("\"b\"".to_owned()),
+                    ],
+                    None,
+                    "d.a.b",
+                ),
+                PickedField::new(
+                    vec![Selector::Identifier("\"c\"".to_owned())],
+                    Some("name".to_owned()),
+                    "d.c",
+                ),
+            ]
+        };
+
+        let mut output = vec![];
+        pick_fields(fields(), false, &mut input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"b\":1,\"name\":\"x\"}\n{\"b\":null,\"name\":\"y\"}\n"
+        );
+
+        let mut output = vec![];
+        pick_fields(fields(), true, &mut input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[1,\"x\"]\n[null,\"y\"]\n"
+        );
+
+        let duplicates = vec![
+            PickedField::new(
+                vec![
+                    Selector::Identifier("\"a\"".to_owned()),
+                    Selector::Identifier("\"id\"".to_owned()),
+                ],
+                None,
+                "d.a.id",
+            ),
+            PickedField::new(
+                vec![
+                    Selector::Identifier("\"b\"".to_owned()),
+                    Selector::Identifier("\"id\"".to_owned()),
+                ],
+                None,
+                "d.b.id",
+            ),
+        ];
+        assert!(pick_fields(duplicates, false, &mut input.as_bytes(), &mut vec![]).is_err());
+    }
 }
@@ -1,46 +1,337 @@
-use crate::{filter::select_from_json_object, join::OrderedValue};
-use itertools::Itertools;
+use crate::{
+    external_sort::{external_sort, Key},
+    filter::select_value,
+    join::{OrderedNumber, OrderedValue},
+};
 use ndjson_common::{
-    error::NdJsonSpatialError, json_selector_parser::Selector, ndjson::NdjsonReader,
+    error::NdJsonSpatialError,
+    json_selector_parser::{parse_json_selector, split_selector_suffix, Selector},
+    ndjson::NdjsonReader,
 };
 
+use serde_json::Value;
 use std::{
     cmp::Ordering,
+    collections::BinaryHeap,
     io::{BufRead, Write},
 };
 
+/// A field to sort by and how to order its values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortField {
+    pub selectors: Vec<Selector>,
+    pub ascending: bool,
+    /// Put null and missing values first instead of last, in either direction.
+    pub nulls_first: bool,
+    pub strings: StringOrder,
+    /// Lowercase strings before comparing them.
+    pub fold_case: bool,
+}
+
+/// How string values are compared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StringOrder {
+    /// By character.
+    Lexical,
+    /// Strings that parse as numbers compare as numbers.
+    Numeric,
+    /// Runs of digits compare as numbers, so file2 < file10 and 1.9.0 < 1.10.0.
+    Natural,
+    /// Numbers are compared as strings too.
+    AllLexical,
+}
+
+impl From<(Vec<Selector>, bool)> for SortField {
+    fn from((selectors, ascending): (Vec<Selector>, bool)) -> Self {
+        SortField {
+            selectors,
+            ascending,
+            nulls_first: false,
+            strings: StringOrder::Lexical,
+            fold_case: false,
+        }
+    }
+}
+
+/// Parses `<selector>[:<option>]...`, where the options are `asc` or `desc`,
+/// `nulls_first` or `nulls_last`, `numeric`, `lexical` or `natural`, and `fold_case`.
+pub fn parse_sort_field(field: &str) -> Result<SortField, NdJsonSpatialError> {
+    let (selector, options) = split_selector_suffix(field);
+
+    let selectors = match parse_json_selector(selector.into()) {
+        Ok((rest, selectors)) if rest.is_empty() => selectors,
+        Ok((rest, _)) => {
+            return Err(NdJsonSpatialError::Error(format!(
+                "Failed to parse selector: {}: unexpected {}",
+                field, rest
+            )))
+        }
+        Err(e) => {
+            return Err(NdJsonSpatialError::Error(format!(
+                "Failed to parse selector: {}: {}",
+                field, e
+            )))
+        }
+    };
+
+    let mut sort_field = SortField::from((selectors, true));
+
+    for option in options.into_iter().flat_map(|o| o.split(':')) {
+        match option {
+            "asc" | "a" => sort_field.ascending = true,
+            "desc" | "d" => sort_field.ascending = false,
+            "nulls_first" => sort_field.nulls_first = true,
+            "nulls_last" => sort_field.nulls_first = false,
+            "lexical" => sort_field.strings = StringOrder::AllLexical,
+            "numeric" => sort_field.strings = StringOrder::Numeric,
+            "natural" | "version" => sort_field.strings = StringOrder::Natural,
+            "fold_case" => sort_field.fold_case = true,
+            other => {
+                return Err(NdJsonSpatialError::Error(format!(
+                    "Unknown sort option {} in {}, expected asc, desc, nulls_first, nulls_last, lexical, numeric, natural or fold_case",
+                    other, field
+                )))
+            }
+        }
+    }
+
+    Ok(sort_field)
+}
+
+/// Sorts the input by the fields, keeping only the first `limit` records if given.
+/// Records past `memory_limit` bytes are sorted in runs on disk and merged.
 pub fn sort<IN: BufRead, OUT: Write>(
     input: IN,
     output: &mut OUT,
-    selectors: Vec<(Vec<Selector>, bool)>,
+    fields: Vec<SortField>,
+    limit: Option<usize>,
+    memory_limit: usize,
 ) -> Result<(), NdJsonSpatialError> {
-    for item in NdjsonReader::new(input).flatten().sorted_by(|left, right| {
-        selectors
-            .iter()
-            .map(|(selector, ascending)| {
-                if *ascending {
-                    Ord::cmp(
-                        &select_from_json_object(left.clone(), selector).map(OrderedValue::from),
-                        &select_from_json_object(right.clone(), selector).map(OrderedValue::from),
-                    )
-                } else {
-                    Ord::cmp(
-                        &select_from_json_object(right.clone(), selector).map(OrderedValue::from),
-                        &select_from_json_object(left.clone(), selector).map(OrderedValue::from),
-                    )
-                }
-            })
+    let compare = |left: &Key, right: &Key| {
+        left.chunks(2)
+            .zip(right.chunks(2))
+            .zip(&fields)
+            .map(|((left, right), field)| compare_field(left, right, field))
             .fold(Ordering::Equal, |acc, item| acc.then(item))
-    }) {
+    };
+
+    let records = NdjsonReader::new(input)
+        .enumerate()
+        .filter_map(|(index, value)| match value {
+            Ok(value) => Some(Ok((sort_key(&value, &fields), value))),
+            Err(e) => {
+                writeln!(
+                    std::io::stderr(),
+                    "Skipping line {} which is not json: {:?}",
+                    index + 1,
+                    e
+                )
+                .expect("Unable to write to stderr");
+                None
+            }
+        });
+
+    if let Some(limit) = limit {
+        for (_, item) in top_k(records, &compare, limit)? {
+            writeln!(output, "{}", item).expect("Unable to write to stdout");
+        }
+        return Ok(());
+    }
+
+    for record in external_sort(records, &compare, memory_limit)? {
+        let (_, item) = record?;
         writeln!(output, "{}", item).expect("Unable to write to stdout");
     }
 
     Ok(())
 }
 
+/// The first of each field's two key values, telling what the second holds.
+const VALUE: u64 = 0;
+const NULL: u64 = 1;
+/// A string split by `natural_chunks`, which still sorts among strings.
+const CHUNKED_STRING: u64 = 2;
+
+/// Two values per field: a tag, and the field's value prepared for comparison, so each
+/// record's key is computed once.
+fn sort_key(value: &Value, fields: &[SortField]) -> Key {
+    let mut key = Vec::with_capacity(2 * fields.len());
+    for field in fields {
+        let (tag, value) = match select_value(value, &field.selectors) {
+            Some(Value::Null) | None => (NULL, OrderedValue::Null),
+            Some(v) => prepare(v.into(), field),
+        };
+        key.push(OrderedValue::Number(OrderedNumber::PosInt(tag)));
+        key.push(value);
+    }
+    key
+}
+
+fn prepare(value: OrderedValue, field: &SortField) -> (u64, OrderedValue) {
+    let value = match value {
+        OrderedValue::String(s) if field.fold_case => OrderedValue::String(s.to_lowercase()),
+        v => v,
+    };
+    match (field.strings, value) {
+        (StringOrder::Numeric, OrderedValue::String(s)) => match OrderedNumber::parse(s.trim()) {
+            Some(n) => (VALUE, OrderedValue::Number(n)),
+            None => (VALUE, OrderedValue::String(s)),
+        },
+        (StringOrder::Natural, OrderedValue::String(s)) => (CHUNKED_STRING, natural_chunks(&s)),
+        (StringOrder::AllLexical, OrderedValue::Number(n)) => {
+            (VALUE, OrderedValue::String(Value::from(n).to_string()))
+        }
+        (_, v) => (VALUE, v),
+    }
+}
+
+/// Splits a string into runs of digits, as numbers, and runs of other characters.
+fn natural_chunks(s: &str) -> OrderedValue {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let is_digit = c.is_ascii_digit();
+        let end = match chars.peek() {
+            Some((next, n)) if n.is_ascii_digit() != is_digit => *next,
+            Some(_) => continue,
+            None => s.len(),
+        };
+        let chunk = &s[start..end];
+        chunks.push(match chunk.parse::<u64>() {
+            Ok(n) if is_digit => OrderedValue::Number(OrderedNumber::PosInt(n)),
+            _ if is_digit => OrderedNumber::parse(chunk)
+                .map(OrderedValue::Number)
+                .unwrap_or_else(|| OrderedValue::String(chunk.to_string())),
+            _ => OrderedValue::String(chunk.to_string()),
+        });
+        start = end;
+    }
+    OrderedValue::Array(chunks)
+}
+
+fn compare_field(left: &[OrderedValue], right: &[OrderedValue], field: &SortField) -> Ordering {
+    let tag = |key: &[OrderedValue]| match key[0] {
+        OrderedValue::Number(OrderedNumber::PosInt(tag)) => tag,
+        _ => VALUE,
+    };
+    let (left, right) = ((tag(left), &left[1]), (tag(right), &right[1]));
+
+    match (left.0 == NULL, right.0 == NULL) {
+        (true, true) => Ordering::Equal,
+        (true, false) if field.nulls_first => Ordering::Less,
+        (true, false) => Ordering::Greater,
+        (false, true) if field.nulls_first => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        _ if field.ascending => compare_tagged(left, right),
+        _ => compare_tagged(right, left),
+    }
+}
+
+fn compare_tagged(left: (u64, &OrderedValue), right: (u64, &OrderedValue)) -> Ordering {
+    let rank_of = |(tag, value): (u64, &OrderedValue)| {
+        if tag == CHUNKED_STRING {
+            rank(&OrderedValue::String(String::new()))
+        } else {
+            rank(value)
+        }
+    };
+    rank_of(left)
+        .cmp(&rank_of(right))
+        .then_with(|| compare_values(left.1, right.1))
+}
+
+/// Numbers first, then strings, booleans, arrays and objects.
+fn rank(value: &OrderedValue) -> u8 {
+    match value {
+        OrderedValue::Number(_) => 0,
+        OrderedValue::String(_) => 1,
+        OrderedValue::Bool(_) => 2,
+        OrderedValue::Array(_) => 3,
+        OrderedValue::Object(_) => 4,
+        OrderedValue::Null => 5,
+    }
+}
+
+/// Orders numbers by value, then strings, booleans, arrays and objects.
+fn compare_values(left: &OrderedValue, right: &OrderedValue) -> Ordering {
+    match (left, right) {
+        (OrderedValue::Number(l), OrderedValue::Number(r)) => {
+            l.numeric_cmp(r).unwrap_or(Ordering::Equal)
+        }
+        (OrderedValue::Array(l), OrderedValue::Array(r)) => l
+            .iter()
+            .zip(r)
+            .map(|(l, r)| compare_values(l, r))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| l.len().cmp(&r.len())),
+        (l, r) if rank(l) == rank(r) => l.cmp(r),
+        (l, r) => rank(l).cmp(&rank(r)),
+    }
+}
+
+struct TopEntry<'a> {
+    key: Key,
+    index: usize,
+    value: Value,
+    compare: &'a dyn Fn(&Key, &Key) -> Ordering,
+}
+
+impl<'a> PartialEq for TopEntry<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a> Eq for TopEntry<'a> {}
+
+impl<'a> PartialOrd for TopEntry<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for TopEntry<'a> {
+    /// Later records are greater on ties, so the sort stays stable.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.compare)(&self.key, &other.key).then(self.index.cmp(&other.index))
+    }
+}
+
+/// The first `limit` records in sorted order, holding at most `limit` records in a
+/// max-heap that drops the greatest whenever it overflows.
+fn top_k<I>(
+    records: I,
+    compare: &dyn Fn(&Key, &Key) -> Ordering,
+    limit: usize,
+) -> Result<Vec<(Key, Value)>, NdJsonSpatialError>
+where
+    I: Iterator<Item = Result<(Key, Value), NdJsonSpatialError>>,
+{
+    let mut heap = BinaryHeap::with_capacity(limit + 1);
+    for (index, record) in records.enumerate() {
+        let (key, value) = record?;
+        heap.push(TopEntry {
+            key,
+            index,
+            value,
+            compare,
+        });
+        if heap.len() > limit {
+            heap.pop();
+        }
+    }
+    Ok(heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::external_sort::DEFAULT_MEMORY_LIMIT;
 
     #[test]
     fn test_sort_already_sorted() {
@@ -51,7 +342,9 @@ mod tests {
         sort(
             input.as_bytes(),
             &mut output,
-            vec![(vec![Selector::Identifier("bar".into())], true)],
+            vec![(vec![Selector::Identifier("bar".into())], true).into()],
+            None,
+            DEFAULT_MEMORY_LIMIT,
         )
         .unwrap();
 
@@ -63,9 +356,11 @@ mod tests {
             input.as_bytes(),
             &mut output,
             vec![
-                (vec![Selector::Identifier("foo".into())], true),
-                (vec![Selector::Identifier("bar".into())], true),
+                (vec![Selector::Identifier("foo".into())], true).into(),
+                (vec![Selector::Identifier("bar".into())], true).into(),
             ],
+            None,
+            DEFAULT_MEMORY_LIMIT,
         )
         .unwrap();
 
@@ -81,7 +376,9 @@ mod tests {
         sort(
             input.as_bytes(),
             &mut output,
-            vec![(vec![Selector::Identifier("bar".into())], false)],
+            vec![(vec![Selector::Identifier("bar".into())], false).into()],
+            None,
+            DEFAULT_MEMORY_LIMIT,
         )
         .unwrap();
 
@@ -90,4 +387,101 @@ mod tests {
             "{\"bar\":4,\"foo\":7}\n{\"bar\":3,\"foo\":4}\n".as_bytes()
         );
     }
+
+    #[test]
+    fn test_sort_spills() {
+        let input = (0..50)
+            .map(|i| format!("{{\"bar\":{},\"foo\":{}}}\n", (i * 7) % 10, i))
+            .chain(vec!["{\"foo\":50}\n".to_string()])
+            .collect::<String>();
+
+        let mut output = vec![];
+
+        sort(
+            input.as_bytes(),
+            &mut output,
+            vec![
+                (vec![Selector::Identifier("bar".into())], false).into(),
+                (vec![Selector::Identifier("foo".into())], true).into(),
+            ],
+            None,
+            100,
+        )
+        .unwrap();
+
+        let values = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(values.len(), 51);
+        assert_eq!(values[0], serde_json::json!({"bar": 9, "foo": 7}));
+        assert_eq!(values[1], serde_json::json!({"bar": 9, "foo": 17}));
+        assert_eq!(values[49], serde_json::json!({"bar": 0, "foo": 40}));
+        assert_eq!(values[50], serde_json::json!({"foo": 50}));
+    }
+
+    #[test]
+    fn test_sort_options() {
+        let input = "{\"v\":\"file10\"}\nnot json\n{\"v\":-3}\n{\"v\":null}\n{\"v\":\"File2\"}\n{\"v\":2.5}\n{\"v\":\"12\"}\n";
+
+        let run = |field: &str, limit| {
+            let mut output = vec![];
+            sort(
+                input.as_bytes(),
+                &mut output,
+                vec![parse_sort_field(field).unwrap()],
+                limit,
+                DEFAULT_MEMORY_LIMIT,
+            )
+            .unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|l| l.to_owned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        assert_eq!(
+            run("d.v", None),
+            "{\"v\":-3} {\"v\":2.5} {\"v\":\"12\"} {\"v\":\"File2\"} {\"v\":\"file10\"} {\"v\":null}"
+        );
+        assert_eq!(
+            run("d.v:numeric:desc:nulls_first", None),
+            "{\"v\":null} {\"v\":\"file10\"} {\"v\":\"File2\"} {\"v\":\"12\"} {\"v\":2.5} {\"v\":-3}"
+        );
+        assert_eq!(
+            run("d.v:natural:fold_case", None),
+            "{\"v\":-3} {\"v\":2.5} {\"v\":\"12\"} {\"v\":\"File2\"} {\"v\":\"file10\"} {\"v\":null}"
+        );
+        assert_eq!(
+            run("d.v:d", Some(2)),
+            "{\"v\":\"file10\"} {\"v\":\"File2\"}"
+        );
+
+        assert!(parse_sort_field("d.v:sideways").is_err());
+    }
+
+    #[test]
+    fn test_sort_natural_mixed_types() {
+        let input = "{\"v\":true}\n{\"v\":[1]}\n{\"v\":\"file10\"}\n{\"v\":3}\n{\"v\":\"file2\"}\n";
+
+        let mut output = vec![];
+
+        sort(
+            input.as_bytes(),
+            &mut output,
+            vec![parse_sort_field("d.v:natural").unwrap()],
+            None,
+            DEFAULT_MEMORY_LIMIT,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"v\":3}\n{\"v\":\"file2\"}\n{\"v\":\"file10\"}\n{\"v\":true}\n{\"v\":[1]}\n"
+        );
+    }
 }